@@ -1,4 +1,10 @@
+use crate::{
+    ping::{CandidateOrder, ProbeMode},
+    selection::SelectionStrategy,
+};
+
 use clap::Parser;
+use std::net::IpAddr;
 
 /// Cloudflare IP Speedtest Backend
 #[derive(Parser, Debug, Clone)]
@@ -33,6 +39,251 @@ pub struct Args {
     /// Disable Auto Upgrade Mode
     #[arg(long, default_value_t = false)]
     pub disable_auto_upgrade: bool,
+
+    // 从本地文件刷新 Cloudflare IP 段目录
+    /// Load Cloudflare IP Ranges From A Local File
+    #[arg(long)]
+    pub ip_ranges_file: Option<String>,
+
+    // 仅使用 IPv4 段
+    /// Only Use IPv4 Ranges
+    #[arg(long, default_value_t = false, conflicts_with = "ipv6_only")]
+    pub ipv4_only: bool,
+
+    // 仅使用 IPv6 段
+    /// Only Use IPv6 Ranges
+    #[arg(long, default_value_t = false)]
+    pub ipv6_only: bool,
+
+    // 排除的 IP 前缀
+    /// Exclude These Prefixes From IP Ranges (Comma Separated CIDRs)
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_prefix: Vec<String>,
+
+    // 排除列表文件
+    /// Load Excluded Prefixes From A Local File (One CIDR Per Line)
+    #[arg(long)]
+    pub exclude_file: Option<String>,
+
+    // 允许的 IP 前缀
+    /// Only Probe Addresses Within These Prefixes (Comma Separated CIDRs)
+    #[arg(long, value_delimiter = ',')]
+    pub allow_prefix: Vec<String>,
+
+    // 允许列表文件
+    /// Load Allowed Prefixes From A Local File (One CIDR Per Line)
+    #[arg(long)]
+    pub allow_file: Option<String>,
+
+    // 每个子网采样数量
+    /// Random Addresses Sampled Per /24 (IPv4) Or /120 (IPv6), 0 To Disable Sampling
+    #[arg(long, default_value_t = 0)]
+    pub sample_per_subnet: u32,
+
+    // 采样总数上限
+    /// Maximum Number Of Sampled Addresses Per Task, 0 For No Limit
+    #[arg(long, default_value_t = 0)]
+    pub sample_max_total: usize,
+
+    // 采样随机种子
+    /// Random Seed For Deterministic Sampling
+    #[arg(long)]
+    pub sample_seed: Option<u64>,
+
+    // 每个 IP 的 Ping 次数
+    /// Number Of Probes Per IP
+    #[arg(long, default_value_t = 3)]
+    pub ping_count: u32,
+
+    // 每次 Ping 的间隔
+    /// Interval Between Probes To The Same IP (in Milliseconds)
+    #[arg(long, default_value_t = 200)]
+    pub ping_interval_ms: u64,
+
+    // 丢包率上限, 超过时该 IP 不参与测速
+    /// Drop IPs Whose Probe Loss Exceeds This Percentage
+    #[arg(long, default_value_t = 50.0)]
+    pub ping_max_loss: f64,
+
+    // 延迟探测方式, 主端下发的任务可覆盖该设置
+    /// Latency Probe Mode
+    #[arg(long, value_enum, default_value_t = ProbeMode::Tcp)]
+    pub probe_mode: ProbeMode,
+
+    // TLS 探测时请求 /cdn-cgi/trace
+    /// Request /cdn-cgi/trace In TLS Probe Mode To Record TTFB And Colo
+    #[arg(long, default_value_t = false)]
+    pub probe_trace: bool,
+
+    // 候选 IP 交给测速的顺序
+    /// Order In Which Probed IPs Are Speed Tested: arrival, batch (default, sorted per completed batch) Or full (sorted after all probes finish, the only deterministic order)
+    #[arg(long, value_enum, default_value_t = CandidateOrder::Batch)]
+    pub candidate_order: CandidateOrder,
+
+    // 探测端口列表
+    /// Ports To Probe (Comma Separated), Defaults To 80 For TCP And 443 For TLS
+    #[arg(long, value_delimiter = ',')]
+    pub probe_ports: Vec<u16>,
+
+    // 同时进行的探测数量上限
+    /// Maximum Number Of Concurrent Probes
+    #[arg(long, default_value_t = 100)]
+    pub probe_concurrency: usize,
+
+    // 每秒发起的探测数量上限
+    /// Maximum Probes Started Per Second, 0 For Unlimited
+    #[arg(long, default_value_t = 0)]
+    pub probe_max_pps: u32,
+
+    // 自适应调整探测并发数
+    /// Back Off Probe Concurrency When Resource Errors Or Timeouts Increase
+    #[arg(long, default_value_t = false)]
+    pub probe_adaptive: bool,
+
+    // 探测与测速绑定的本地地址
+    /// Bind Probes And Speed Tests To These Local Addresses (At Most One IPv4 And One IPv6, Comma Separated)
+    #[arg(long, value_delimiter = ',')]
+    pub bind_address: Vec<IpAddr>,
+
+    // 探测与测速绑定的网卡
+    /// Bind Probes And Speed Tests To This Network Interface (SO_BINDTODEVICE)
+    #[arg(long)]
+    pub bind_interface: Option<String>,
+
+    // 探测与测速使用的防火墙标记
+    /// Set This Firewall Mark (SO_MARK) On Probe And Speed Test Sockets, Decimal Or 0x-Prefixed Hex
+    #[arg(long, value_parser = parse_fwmark)]
+    pub bind_fwmark: Option<u32>,
+
+    // 命名的出口, 每个任务在所有出口上分别测速
+    /// Define A Named Egress As NAME=SETTING[,SETTING...], Where SETTING Is A Local IP, dev:IFACE Or mark:FWMARK; Repeat For Multiple Egresses
+    #[arg(long, conflicts_with_all = ["bind_address", "bind_interface", "bind_fwmark"])]
+    pub egress: Vec<String>,
+
+    // 与主端通信绑定的本地地址
+    /// Bind The Control Channel To These Local Addresses (At Most One IPv4 And One IPv6, Comma Separated)
+    #[arg(long, value_delimiter = ',')]
+    pub control_bind_address: Vec<IpAddr>,
+
+    // 与主端通信绑定的网卡
+    /// Bind The Control Channel To This Network Interface (SO_BINDTODEVICE)
+    #[arg(long)]
+    pub control_bind_interface: Option<String>,
+
+    // 与主端通信使用的防火墙标记
+    /// Set This Firewall Mark (SO_MARK) On Control Channel Sockets, Decimal Or 0x-Prefixed Hex
+    #[arg(long, value_parser = parse_fwmark)]
+    pub control_bind_fwmark: Option<u32>,
+
+    // 同时测速的 IP 数量, 节点带宽由并发的测速均分, 总速度只在开启限速时受限
+    /// Number Of IPs To Speed Test Concurrently (Total Speed Is Only Capped With --speed-rate-limit)
+    #[arg(long, default_value_t = 1)]
+    pub speed_concurrency: usize,
+
+    // 每个 IP 同时建立的下载连接数
+    /// Number Of Download Connections Per IP
+    #[arg(long, default_value_t = 1)]
+    pub speed_streams: usize,
+
+    // 从单个连接开始逐步增加连接, 直到吞吐量不再增长, 连接数不超过 speed-streams
+    /// Grow Download Connections Until Throughput Plateaus
+    #[arg(long, default_value_t = false)]
+    pub speed_streams_adaptive: bool,
+
+    // 下载开始后不计入稳态速度的预热时间
+    /// Download Warm-Up Excluded From The Steady-State Speed (in Milliseconds)
+    #[arg(long, default_value_t = 2000)]
+    pub speed_warmup_ms: u64,
+
+    // 测速时将所有测速连接的总下载速度限制在最大带宽以内
+    /// Keep Speed Tests Within The Max Bandwidth
+    #[arg(long, default_value_t = false)]
+    pub speed_rate_limit: bool,
+
+    // 确认达到任务的最低速度要求后提前结束测速, 节省流量
+    /// Stop Speed Tests Once The Minimum Speed Is Proven
+    #[arg(long, default_value_t = false)]
+    pub speed_early_stop: bool,
+
+    // 上传测速发送的数据量
+    /// Upload Test Payload Size (in MB)
+    #[arg(long, default_value_t = 25)]
+    pub upload_size_mb: u64,
+
+    // 上传测速的最长时间
+    /// Upload Test Duration Limit (in Seconds)
+    #[arg(long, default_value_t = 10)]
+    pub upload_time_secs: u64,
+
+    // 流量统计文件, 重启后从该文件恢复已用流量; 未设置时仅在设置了流量配额后使用默认路径
+    /// Traffic Usage File, Defaults To /var/lib/cfst_slave/traffic When A Quota Is Set
+    #[arg(long)]
+    pub traffic_file: Option<String>,
+
+    // 每日流量配额, 0 表示不限制
+    /// Daily Traffic Quota (in GB), 0 For Unlimited
+    #[arg(long, default_value_t = 0.0)]
+    pub daily_quota_gb: f64,
+
+    // 每月流量配额, 0 表示不限制
+    /// Monthly Traffic Quota (in GB), 0 For Unlimited
+    #[arg(long, default_value_t = 0.0)]
+    pub monthly_quota_gb: f64,
+
+    // 每月流量配额重置的日期 (UTC)
+    /// Day Of Month The Monthly Quota Resets On (1-28, UTC)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=28))]
+    pub quota_reset_day: u32,
+
+    // 上报 IP 的选择策略, 主端下发的任务可覆盖该设置
+    /// Strategy For Picking The Reported IP
+    #[arg(long, value_enum, default_value_t = SelectionStrategy::FirstFit)]
+    pub select_strategy: SelectionStrategy,
+
+    // top-k 与 weighted 策略测速的候选数量, 仅在整体排序时为整个任务中延迟最低的候选
+    /// Number Of Candidates To Speed Test For top-k And weighted, Lowest Latency Only With --candidate-order full
+    #[arg(long, default_value_t = 5)]
+    pub select_top_k: usize,
+
+    // time-budget 策略的测速时长
+    /// Speed Test Search Time For time-budget, Stops When It Runs Out Even Without A Qualified IP (in Seconds)
+    #[arg(long, default_value_t = 60)]
+    pub select_time_budget_secs: u64,
+
+    // 加权得分中延迟的权重
+    /// Weight Of Median Latency In The weighted Score (Normalized Against The Tested Candidates)
+    #[arg(long, default_value_t = 1.0)]
+    pub select_latency_weight: f64,
+
+    // 加权得分中抖动的权重
+    /// Weight Of Jitter In The weighted Score (Normalized Against The Tested Candidates)
+    #[arg(long, default_value_t = 1.0)]
+    pub select_jitter_weight: f64,
+
+    // 加权得分中速度的权重
+    /// Weight Of Speed In The weighted Score (Normalized Against The Tested Candidates)
+    #[arg(long, default_value_t = 1.0)]
+    pub select_speed_weight: f64,
+
+    // 劫持检测的延迟下限
+    /// Flag IPs With Median Latency Below This As Suspected Interception (in Milliseconds), 0 To Disable
+    #[arg(long, default_value_t = 10)]
+    pub hijack_latency_floor_ms: u64,
+
+    // 劫持检测时校验 TLS 证书
+    /// Validate The TLS Certificate Of Candidates To Detect Interception
+    #[arg(long, default_value_t = false)]
+    pub hijack_check_tls: bool,
+
+    // 劫持检测时检查 /cdn-cgi/trace
+    /// Check /cdn-cgi/trace Of Candidates To Detect Interception
+    #[arg(long, default_value_t = false)]
+    pub hijack_check_trace: bool,
+
+    // 跳过疑似被劫持的 IP
+    /// Skip Suspected Intercepted IPs Instead Of Only Flagging Them
+    #[arg(long, default_value_t = false)]
+    pub skip_suspected: bool,
 }
 
 /**
@@ -44,7 +295,7 @@ pub struct Args {
  * @return String 返回一个字符串, 格式为"IP地址:端口号"。
  */
 fn return_default_server() -> String {
-    return "backend.cloudflare.su:2333".to_string();
+    "backend.cloudflare.su:2333".to_string()
 }
 
 /**
@@ -56,7 +307,22 @@ fn return_default_server() -> String {
  * @return 字符串类型的默认启动令牌。
  */
 fn return_default_bootstrap_token() -> String {
-    return "cfst1234".to_string();
+    "cfst1234".to_string()
+}
+
+/**
+ * 解析防火墙标记。
+ *
+ * 支持十进制或以 0x 开头的十六进制, 与 iptables / ip rule 中的写法一致。
+ *
+ * @return 解析得到的防火墙标记, 格式错误时返回错误信息。
+ */
+pub fn parse_fwmark(value: &str) -> Result<u32, String> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse::<u32>(),
+    };
+    parsed.map_err(|e| format!("无效的防火墙标记 {}: {}", value, e))
 }
 
 /**
@@ -70,7 +336,6 @@ fn return_default_bootstrap_token() -> String {
  */
 pub fn init_args() -> Args {
     // 使用Args::parse方法从命令行参数中构建Args对象。
-    let args: Args = Args::parse();
     // 返回构建好的Args对象。
-    return args;
+    Args::parse()
 }
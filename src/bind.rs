@@ -0,0 +1,307 @@
+use crate::args::{parse_fwmark, Args};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{
+    error::Error,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// 出站套接字的绑定设置, 用于在多出口的机器上指定流量走哪条线路。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SocketBinding {
+    /// 连接 IPv4 地址时绑定的本地地址
+    pub address_v4: Option<Ipv4Addr>,
+    /// 连接 IPv6 地址时绑定的本地地址
+    pub address_v6: Option<Ipv6Addr>,
+    /// 绑定的网卡 (SO_BINDTODEVICE)
+    pub interface: Option<String>,
+    /// 设置的防火墙标记 (SO_MARK), 可配合策略路由使用
+    pub fwmark: Option<u32>,
+}
+
+impl SocketBinding {
+    /// 根据本地地址列表 (每个地址族至多一个)、网卡与防火墙标记构建绑定设置。
+    pub fn new(
+        addresses: &[IpAddr],
+        interface: Option<String>,
+        fwmark: Option<u32>,
+    ) -> Result<SocketBinding, Box<dyn Error>> {
+        let mut binding = SocketBinding {
+            interface,
+            fwmark,
+            ..SocketBinding::default()
+        };
+        for address in addresses {
+            match address {
+                IpAddr::V4(v4) if binding.address_v4.is_none() => binding.address_v4 = Some(*v4),
+                IpAddr::V6(v6) if binding.address_v6.is_none() => binding.address_v6 = Some(*v6),
+                _ => return Err(format!("每个地址族只能绑定一个本地地址: {}", address).into()),
+            }
+        }
+        Ok(binding)
+    }
+
+    /// 根据命令行参数构建探测与测速使用的绑定设置。
+    pub fn probe_from_args(args: &Args) -> Result<SocketBinding, Box<dyn Error>> {
+        SocketBinding::new(
+            &args.bind_address,
+            args.bind_interface.clone(),
+            args.bind_fwmark,
+        )
+    }
+
+    /// 根据命令行参数构建与主端通信使用的绑定设置。
+    pub fn control_from_args(args: &Args) -> Result<SocketBinding, Box<dyn Error>> {
+        SocketBinding::new(
+            &args.control_bind_address,
+            args.control_bind_interface.clone(),
+            args.control_bind_fwmark,
+        )
+    }
+
+    /// 是否未设置任何绑定, 即由内核自行选择出口。
+    pub fn is_default(&self) -> bool {
+        *self == SocketBinding::default()
+    }
+
+    /// 返回连接指定地址时绑定的本地地址。
+    pub fn local_address(&self, target: &SocketAddr) -> Option<IpAddr> {
+        match target {
+            SocketAddr::V4(_) => self.address_v4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.address_v6.map(IpAddr::V6),
+        }
+    }
+
+    /// 对即将连接指定地址的套接字应用绑定设置。
+    pub fn apply(&self, socket: &Socket, target: &SocketAddr) -> io::Result<()> {
+        if let Some(interface) = &self.interface {
+            bind_device(socket, interface)?;
+        }
+        if let Some(mark) = self.fwmark {
+            set_mark(socket, mark)?;
+        }
+        if let Some(address) = self.local_address(target) {
+            socket.bind(&SockAddr::from(SocketAddr::new(address, 0)))?;
+        }
+        Ok(())
+    }
+
+    /// 按绑定设置建立 TCP 连接。
+    pub async fn connect(&self, target: SocketAddr) -> io::Result<TcpStream> {
+        if self.is_default() {
+            return TcpStream::connect(target).await;
+        }
+
+        let socket = Socket::new(
+            Domain::for_address(target),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        socket.set_nonblocking(true)?;
+        self.apply(&socket, &target)?;
+
+        let socket: std::net::TcpStream = socket.into();
+        TcpSocket::from_std_stream(socket).connect(target).await
+    }
+
+    /// 按绑定设置创建 UDP 套接字, 仅绑定与目标地址同一地址族的本地地址。
+    pub fn udp_socket(&self, target: &SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(*target),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        socket.set_nonblocking(true)?;
+        self.apply(&socket, target)?;
+        if self.local_address(target).is_none() {
+            let unspecified: IpAddr = match target {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            socket.bind(&SockAddr::from(SocketAddr::new(unspecified, 0)))?;
+        }
+
+        let socket: std::net::UdpSocket = socket.into();
+        UdpSocket::from_std(socket)
+    }
+}
+
+impl fmt::Display for SocketBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_default() {
+            return write!(f, "默认路由");
+        }
+
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(address) = self.address_v4 {
+            parts.push(address.to_string());
+        }
+        if let Some(address) = self.address_v6 {
+            parts.push(address.to_string());
+        }
+        if let Some(interface) = &self.interface {
+            parts.push(format!("网卡 {}", interface));
+        }
+        if let Some(mark) = self.fwmark {
+            parts.push(format!("fwmark {:#x}", mark));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// 命名的出口, 多线路机器可定义多个出口, 每个任务会在所有出口上分别测速。
+#[derive(Debug, Clone)]
+pub struct Egress {
+    /// 出口名称, 随结果上报; 未定义出口时为空
+    pub name: String,
+    /// 出口的绑定设置
+    pub binding: SocketBinding,
+}
+
+impl Egress {
+    /// 根据命令行参数构建出口列表, 未定义出口时返回使用 `--bind-*` 设置的单个默认出口。
+    pub fn from_args(args: &Args) -> Result<Vec<Egress>, Box<dyn Error>> {
+        if args.egress.is_empty() {
+            return Ok(vec![Egress {
+                name: String::new(),
+                binding: SocketBinding::probe_from_args(args)?,
+            }]);
+        }
+
+        let mut egresses: Vec<Egress> = Vec::with_capacity(args.egress.len());
+        for spec in &args.egress {
+            let egress = Egress::parse(spec)?;
+            if egresses.iter().any(|existing| existing.name == egress.name) {
+                return Err(format!("出口名称重复: {}", egress.name).into());
+            }
+            egresses.push(egress);
+        }
+        Ok(egresses)
+    }
+
+    /// 解析 `名称=设置[,设置...]` 格式的出口定义。
+    ///
+    /// 设置可以是本地 IP 地址、`dev:网卡` 或 `mark:防火墙标记`, 如 `ct=192.0.2.2,dev:eth1`。
+    pub fn parse(spec: &str) -> Result<Egress, Box<dyn Error>> {
+        let (name, settings) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("出口定义缺少名称: {}", spec))?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("出口定义缺少名称: {}", spec).into());
+        }
+
+        let mut addresses: Vec<IpAddr> = Vec::new();
+        let mut interface: Option<String> = None;
+        let mut fwmark: Option<u32> = None;
+        for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            if let Some(device) = setting.strip_prefix("dev:") {
+                interface = Some(device.to_string());
+            } else if let Some(mark) = setting.strip_prefix("mark:") {
+                fwmark = Some(parse_fwmark(mark)?);
+            } else {
+                addresses.push(
+                    setting
+                        .parse()
+                        .map_err(|e| format!("出口 {} 的设置 {} 无效: {}", name, setting, e))?,
+                );
+            }
+        }
+
+        let binding = SocketBinding::new(&addresses, interface, fwmark)?;
+        if binding.is_default() {
+            return Err(format!("出口 {} 未设置本地地址、网卡或防火墙标记", name).into());
+        }
+        Ok(Egress {
+            name: name.to_string(),
+            binding,
+        })
+    }
+
+    /// 返回用于日志的出口名称, 默认出口显示为 `默认`。
+    pub fn label(&self) -> &str {
+        if self.name.is_empty() {
+            "默认"
+        } else {
+            &self.name
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "当前系统不支持绑定网卡",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "当前系统不支持设置 fwmark",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_egress_settings() {
+        let egress = Egress::parse("ct=192.0.2.2, dev:eth1").unwrap();
+        assert_eq!(egress.name, "ct");
+        assert_eq!(egress.label(), "ct");
+        assert_eq!(egress.binding.address_v4, Some(Ipv4Addr::new(192, 0, 2, 2)));
+        assert_eq!(egress.binding.address_v6, None);
+        assert_eq!(egress.binding.interface.as_deref(), Some("eth1"));
+
+        let egress = Egress::parse("cu=mark:0x20,2001:db8::2,198.51.100.7").unwrap();
+        assert_eq!(egress.binding.fwmark, Some(0x20));
+        assert_eq!(
+            egress.binding.address_v4,
+            Some(Ipv4Addr::new(198, 51, 100, 7))
+        );
+        assert_eq!(
+            egress.binding.address_v6,
+            Some("2001:db8::2".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_egress_rejects_invalid() {
+        for spec in [
+            "192.0.2.2",
+            "=192.0.2.2",
+            "ct=",
+            "ct=dev:eth1,bogus",
+            "ct=192.0.2.2,192.0.2.3",
+            "ct=mark:zz",
+        ] {
+            assert!(Egress::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn default_egress_label() {
+        let egress = Egress {
+            name: String::new(),
+            binding: SocketBinding::default(),
+        };
+        assert_eq!(egress.label(), "默认");
+        assert!(egress.binding.is_default());
+    }
+}
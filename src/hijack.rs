@@ -0,0 +1,137 @@
+use crate::{
+    args::Args,
+    bind::SocketBinding,
+    ping::{PingStats, ProbeMode},
+    trace::{tls_handshake, TlsTiming},
+    traffic::record_traffic,
+};
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::time::timeout;
+
+// 劫持检测中 TLS 握手与 trace 请求的超时时间
+const HIJACK_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 劫持 (透明代理) 检测设置。
+#[derive(Debug, Clone)]
+pub struct HijackConfig {
+    /// 延迟下限 (毫秒), 延迟中位数低于该值视为疑似劫持, 0 表示不检查
+    pub latency_floor_ms: u128,
+    /// 是否通过 TLS 握手校验证书
+    pub check_tls: bool,
+    /// 是否请求 /cdn-cgi/trace 检查响应
+    pub check_trace: bool,
+    /// 是否跳过疑似被劫持的 IP, 否则仅标记
+    pub skip_suspected: bool,
+}
+
+impl HijackConfig {
+    /// 根据命令行参数构建劫持检测设置。
+    pub fn from_args(args: &Args) -> HijackConfig {
+        HijackConfig {
+            latency_floor_ms: args.hijack_latency_floor_ms as u128,
+            check_tls: args.hijack_check_tls,
+            check_trace: args.hijack_check_trace,
+            skip_suspected: args.skip_suspected,
+        }
+    }
+}
+
+/// 疑似劫持的迹象。
+#[derive(Debug, Clone)]
+pub enum InterceptionSign {
+    /// 延迟中位数低于设置的下限
+    LatencyBelowFloor { median: u128, floor: u128 },
+    /// TLS 证书校验失败
+    InvalidCertificate(String),
+    /// /cdn-cgi/trace 响应不像来自 Cloudflare
+    UnexpectedTrace(String),
+}
+
+impl fmt::Display for InterceptionSign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterceptionSign::LatencyBelowFloor { median, floor } => {
+                write!(f, "延迟 {}ms 低于下限 {}ms", median, floor)
+            }
+            InterceptionSign::InvalidCertificate(e) => write!(f, "TLS 证书校验失败: {}", e),
+            InterceptionSign::UnexpectedTrace(e) => write!(f, "/cdn-cgi/trace 响应异常: {}", e),
+        }
+    }
+}
+
+/// 检测单个 IP 是否疑似被 ISP 透明代理等方式劫持, 返回发现的所有迹象。
+///
+/// 依次检查延迟下限、TLS 证书与 `/cdn-cgi/trace` 响应。TLS 检查使用任务的 SNI,
+/// 若探测方式为 TLS 则连接该 IP 表现最好的端口, 否则连接 443 端口。
+/// TLS 探测中已发现证书校验失败时直接标记, 不再额外握手。
+/// 连接失败或超时不视为劫持迹象。
+pub async fn detect_interception(
+    ip: IpAddr,
+    stats: &PingStats,
+    mode: ProbeMode,
+    sni: &str,
+    binding: &SocketBinding,
+    config: &HijackConfig,
+) -> Vec<InterceptionSign> {
+    let mut signs: Vec<InterceptionSign> = Vec::new();
+
+    if config.latency_floor_ms > 0 && stats.median < config.latency_floor_ms {
+        signs.push(InterceptionSign::LatencyBelowFloor {
+            median: stats.median,
+            floor: config.latency_floor_ms,
+        });
+    }
+
+    // TLS 探测时证书校验失败的 IP 仍会通过探测, 在此直接标记, 无需再次握手
+    let probe_certificate_error = stats
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.certificate_error.clone());
+    if let Some(e) = &probe_certificate_error {
+        signs.push(InterceptionSign::InvalidCertificate(e.clone()));
+    }
+
+    if (config.check_tls || config.check_trace) && probe_certificate_error.is_none() {
+        let port = if mode == ProbeMode::Tls {
+            stats.port
+        } else {
+            443
+        };
+        let addr = SocketAddr::new(ip, port);
+        record_traffic(ProbeMode::Tls.probe_bytes(ip, config.check_trace));
+        match timeout(
+            HIJACK_CHECK_TIMEOUT,
+            tls_handshake(addr, sni, config.check_trace, binding),
+        )
+        .await
+        {
+            Ok(Ok(TlsTiming {
+                certificate_error: Some(e),
+                ..
+            })) => signs.push(InterceptionSign::InvalidCertificate(e)),
+            Ok(Ok(timing)) if config.check_trace => match timing.colo {
+                Some(colo) if is_colo_code(&colo) => {}
+                Some(colo) => signs.push(InterceptionSign::UnexpectedTrace(format!(
+                    "无效的 colo 字段: {}",
+                    colo
+                ))),
+                None => signs.push(InterceptionSign::UnexpectedTrace(
+                    "响应中缺少 colo 字段".to_string(),
+                )),
+            },
+            _ => {}
+        }
+    }
+
+    signs
+}
+
+// Cloudflare 数据中心代码为三位大写字母 (IATA 机场代码)
+fn is_colo_code(colo: &str) -> bool {
+    colo.len() == 3 && colo.chars().all(|c| c.is_ascii_uppercase())
+}
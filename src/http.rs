@@ -0,0 +1,456 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// 响应头部的长度上限, 超过时视为无效响应
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+// 响应头部的数量上限
+const MAX_HEADERS: usize = 64;
+
+/// 解析后的 HTTP 响应头部。
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    /// 状态码
+    pub status: u16,
+    /// 状态描述
+    pub reason: String,
+    /// 响应头, 名称保持原样
+    pub headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    /// 是否为 2xx 响应。
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// 是否为需要跟随的重定向响应。
+    pub fn is_redirect(&self) -> bool {
+        matches!(self.status, 301 | 302 | 303 | 307 | 308)
+    }
+
+    /// 返回指定名称 (不区分大小写) 的第一个响应头。
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 估算响应头部的字节数, 用于流量统计。
+    pub fn wire_len(&self) -> u64 {
+        // 状态行 "HTTP/1.1 200 OK\r\n" 与头部结尾的空行
+        let status_line = 15 + self.reason.len() as u64;
+        let headers: u64 = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.len() + value.len() + 4) as u64)
+            .sum();
+        status_line + headers + 2
+    }
+
+    /// 根据响应头确定响应体的分帧方式。
+    pub fn framing(&self) -> Result<BodyFraming, io::Error> {
+        let chunked = self.header("Transfer-Encoding").is_some_and(|value| {
+            value
+                .rsplit(',')
+                .next()
+                .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        });
+        if chunked {
+            return Ok(BodyFraming::Chunked);
+        }
+        match self.header("Content-Length") {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map(BodyFraming::Length)
+                .map_err(|_| invalid_data(format!("无效的 Content-Length: {}", value))),
+            None => Ok(BodyFraming::UntilClose),
+        }
+    }
+}
+
+/// 响应体的分帧方式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    /// 由 Content-Length 指定长度
+    Length(u64),
+    /// chunked 传输编码
+    Chunked,
+    /// 读取到连接关闭为止
+    UntilClose,
+}
+
+/// 读取并解析响应头部, 跳过 1xx 临时响应。
+///
+/// 返回解析后的头部与已读取的部分响应体。
+pub async fn read_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<(ResponseHead, Vec<u8>), io::Error> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&buffer) {
+            Ok(httparse::Status::Complete(len)) => {
+                let status = response.code.unwrap_or_default();
+                let head = ResponseHead {
+                    status,
+                    reason: response.reason.unwrap_or_default().to_string(),
+                    headers: response
+                        .headers
+                        .iter()
+                        .map(|header| {
+                            (
+                                header.name.to_string(),
+                                String::from_utf8_lossy(header.value).into_owned(),
+                            )
+                        })
+                        .collect(),
+                };
+                let rest = buffer.split_off(len);
+                if (100..200).contains(&status) && status != 101 {
+                    buffer = rest;
+                    continue;
+                }
+                return Ok((head, rest));
+            }
+            Ok(httparse::Status::Partial) => {}
+            Err(e) => return Err(invalid_data(format!("无效的响应头部: {}", e))),
+        }
+
+        if buffer.len() >= MAX_HEAD_LEN {
+            return Err(invalid_data("响应头部过长".to_string()));
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "读取响应头部时连接被关闭",
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// chunked 编码的解析状态
+#[derive(Debug, Clone, Copy)]
+enum ChunkState {
+    // 读取块大小, 记录是否已读到十六进制数字以及是否已进入块扩展
+    Size {
+        size: u64,
+        digits: bool,
+        extension: bool,
+    },
+    SizeLf {
+        size: u64,
+    },
+    Data(u64),
+    DataCr,
+    DataLf,
+    TrailerStart,
+    Trailer,
+    FinalLf,
+}
+
+impl ChunkState {
+    // 新的块大小行
+    const SIZE_START: ChunkState = ChunkState::Size {
+        size: 0,
+        digits: false,
+        extension: false,
+    };
+}
+
+/// 响应体解码器, 从读取到的数据中统计响应体的字节数, 不含分帧信息。
+#[derive(Debug, Clone)]
+pub struct BodyDecoder {
+    framing: BodyFraming,
+    remaining: u64,
+    chunk: ChunkState,
+    done: bool,
+}
+
+impl BodyDecoder {
+    /// 按分帧方式创建解码器。
+    pub fn new(framing: BodyFraming) -> BodyDecoder {
+        let remaining = match framing {
+            BodyFraming::Length(len) => len,
+            _ => 0,
+        };
+        BodyDecoder {
+            framing,
+            remaining,
+            chunk: ChunkState::SIZE_START,
+            done: framing == BodyFraming::Length(0),
+        }
+    }
+
+    /// 响应体是否已经完整读取。
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// 解码一段读取到的数据, 返回其中响应体的字节数。
+    pub fn decode(&mut self, data: &[u8]) -> Result<u64, io::Error> {
+        if self.done {
+            return Ok(0);
+        }
+        match self.framing {
+            BodyFraming::UntilClose => Ok(data.len() as u64),
+            BodyFraming::Length(_) => {
+                let n = self.remaining.min(data.len() as u64);
+                self.remaining -= n;
+                self.done = self.remaining == 0;
+                Ok(n)
+            }
+            BodyFraming::Chunked => self.decode_chunked(data),
+        }
+    }
+
+    /// 连接关闭时检查响应体是否完整, 仅读取到连接关闭为止的响应体总是完整的。
+    pub fn finish(&self) -> Result<(), io::Error> {
+        if self.done || self.framing == BodyFraming::UntilClose {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "响应体尚未读取完整时连接被关闭",
+            ))
+        }
+    }
+
+    fn decode_chunked(&mut self, data: &[u8]) -> Result<u64, io::Error> {
+        let mut body: u64 = 0;
+        let mut i = 0;
+        while i < data.len() && !self.done {
+            let byte = data[i];
+            self.chunk = match self.chunk {
+                ChunkState::Size {
+                    size,
+                    digits,
+                    extension,
+                } => match byte {
+                    // 大小行必须以至少一位十六进制数字开头, 否则空行会被误当作结束块
+                    b'\r' | b'\n' | b';' | b' ' | b'\t' if !digits => {
+                        return Err(invalid_data("chunk 大小行缺少大小".to_string()))
+                    }
+                    b'\r' => ChunkState::SizeLf { size },
+                    b'\n' => self.after_size(size),
+                    _ if extension => ChunkState::Size {
+                        size,
+                        digits,
+                        extension,
+                    },
+                    b';' | b' ' | b'\t' => ChunkState::Size {
+                        size,
+                        digits,
+                        extension: true,
+                    },
+                    _ => {
+                        let digit = (byte as char)
+                            .to_digit(16)
+                            .ok_or_else(|| invalid_data("无效的 chunk 大小".to_string()))?;
+                        let size = size
+                            .checked_mul(16)
+                            .and_then(|size| size.checked_add(digit as u64))
+                            .ok_or_else(|| invalid_data("chunk 大小溢出".to_string()))?;
+                        ChunkState::Size {
+                            size,
+                            digits: true,
+                            extension: false,
+                        }
+                    }
+                },
+                ChunkState::SizeLf { size } => match byte {
+                    b'\n' => self.after_size(size),
+                    _ => return Err(invalid_data("chunk 大小行缺少换行".to_string())),
+                },
+                ChunkState::Data(remaining) => {
+                    // 块数据整段计入, 无需逐字节处理
+                    let n = remaining.min((data.len() - i) as u64);
+                    body += n;
+                    i += n as usize;
+                    self.chunk = if remaining == n {
+                        ChunkState::DataCr
+                    } else {
+                        ChunkState::Data(remaining - n)
+                    };
+                    continue;
+                }
+                ChunkState::DataCr => match byte {
+                    b'\r' => ChunkState::DataLf,
+                    b'\n' => ChunkState::SIZE_START,
+                    _ => return Err(invalid_data("chunk 数据后缺少换行".to_string())),
+                },
+                ChunkState::DataLf => match byte {
+                    b'\n' => ChunkState::SIZE_START,
+                    _ => return Err(invalid_data("chunk 数据后缺少换行".to_string())),
+                },
+                ChunkState::TrailerStart => match byte {
+                    b'\r' => ChunkState::FinalLf,
+                    b'\n' => {
+                        self.done = true;
+                        ChunkState::TrailerStart
+                    }
+                    _ => ChunkState::Trailer,
+                },
+                ChunkState::Trailer => match byte {
+                    b'\n' => ChunkState::TrailerStart,
+                    _ => ChunkState::Trailer,
+                },
+                ChunkState::FinalLf => match byte {
+                    b'\n' => {
+                        self.done = true;
+                        ChunkState::FinalLf
+                    }
+                    _ => return Err(invalid_data("chunked 响应体结尾缺少换行".to_string())),
+                },
+            };
+            i += 1;
+        }
+        Ok(body)
+    }
+
+    // 块大小行结束, 大小为 0 的块表示响应体结束, 其后为 trailer
+    fn after_size(&self, size: u64) -> ChunkState {
+        if size == 0 {
+            ChunkState::TrailerStart
+        } else {
+            ChunkState::Data(size)
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 将一段数据按给定长度切分后依次解码, 返回响应体的总字节数
+    fn decode_split(decoder: &mut BodyDecoder, data: &[u8], split: usize) -> u64 {
+        data.chunks(split)
+            .map(|part| decoder.decode(part).unwrap())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn read_head_with_content_length() {
+        let mut reader: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloextra";
+        let (head, rest) = read_head(&mut reader).await.unwrap();
+        assert_eq!(head.status, 200);
+        assert!(head.is_success());
+        assert_eq!(head.header("content-length"), Some("5"));
+        assert_eq!(head.framing().unwrap(), BodyFraming::Length(5));
+
+        let mut decoder = BodyDecoder::new(head.framing().unwrap());
+        assert_eq!(decoder.decode(&rest).unwrap(), 5);
+        assert!(decoder.is_done());
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[tokio::test]
+    async fn read_head_split_across_reads() {
+        let mut reader = (&b"HTTP/1.1 404 Not"[..])
+            .chain(&b" Found\r\nTransfer-Enc"[..])
+            .chain(&b"oding: gzip, chunked\r\n\r"[..])
+            .chain(&b"\n5\r\n"[..]);
+        let (head, rest) = read_head(&mut reader).await.unwrap();
+        assert_eq!(head.status, 404);
+        assert_eq!(head.reason, "Not Found");
+        assert_eq!(head.framing().unwrap(), BodyFraming::Chunked);
+        assert_eq!(rest, b"5\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_head_skips_informational_responses() {
+        let mut reader =
+            (&b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a>\r\n\r\n"[..])
+                .chain(&b"HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n"[..]);
+        let (head, rest) = read_head(&mut reader).await.unwrap();
+        assert_eq!(head.status, 413);
+        assert!(!head.is_success());
+        assert!(rest.is_empty());
+        assert!(BodyDecoder::new(head.framing().unwrap()).is_done());
+    }
+
+    #[tokio::test]
+    async fn read_head_rejects_truncated_head() {
+        let mut reader: &[u8] = b"HTTP/1.1 200 OK\r\nContent-";
+        let error = read_head(&mut reader).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn framing_rejects_invalid_content_length() {
+        let head = ResponseHead {
+            status: 200,
+            reason: "OK".to_string(),
+            headers: vec![("Content-Length".to_string(), "abc".to_string())],
+        };
+        assert!(head.framing().is_err());
+    }
+
+    #[test]
+    fn content_length_body_incomplete_on_close() {
+        let mut decoder = BodyDecoder::new(BodyFraming::Length(10));
+        assert_eq!(decoder.decode(b"hello").unwrap(), 5);
+        assert!(!decoder.is_done());
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn chunked_split_across_reads() {
+        let body = b"5\r\nhello\r\n1a;name=value\r\nabcdefghijklmnopqrstuvwxyz\r\n0\r\n\r\n";
+        for split in 1..body.len() {
+            let mut decoder = BodyDecoder::new(BodyFraming::Chunked);
+            assert_eq!(
+                decode_split(&mut decoder, body, split),
+                31,
+                "split {}",
+                split
+            );
+            assert!(decoder.is_done(), "split {}", split);
+            assert!(decoder.finish().is_ok());
+        }
+    }
+
+    #[test]
+    fn chunked_with_trailers() {
+        let body = b"3\r\nabc\r\n0\r\nX-Checksum: 1\r\nX-Other: 2\r\n\r\nnext response";
+        let mut decoder = BodyDecoder::new(BodyFraming::Chunked);
+        assert_eq!(decode_split(&mut decoder, body, 4), 3);
+        assert!(decoder.is_done());
+    }
+
+    #[test]
+    fn chunked_incomplete_on_close() {
+        let mut decoder = BodyDecoder::new(BodyFraming::Chunked);
+        assert_eq!(decoder.decode(b"a\r\n01234").unwrap(), 5);
+        assert!(!decoder.is_done());
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn chunked_rejects_size_line_without_digits() {
+        for body in [&b"\r\n"[..], b";ext\r\n", b" 5\r\n", b"5\r\nhello\r\n\r\n"] {
+            let mut decoder = BodyDecoder::new(BodyFraming::Chunked);
+            assert!(decoder.decode(body).is_err(), "{:?}", body);
+            assert!(!decoder.is_done());
+        }
+    }
+
+    #[test]
+    fn chunked_rejects_invalid_size() {
+        let mut decoder = BodyDecoder::new(BodyFraming::Chunked);
+        assert!(decoder.decode(b"5g\r\n").is_err());
+
+        let mut decoder = BodyDecoder::new(BodyFraming::Chunked);
+        assert!(decoder.decode(b"11111111111111111\r\n").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    bind::SocketBinding,
+    probe::{ProbeOutcome, ProbeResult},
+    raw::{internet_checksum, skip_ipv4_header, unspecified_address, PacketHandler, Registry},
+};
+
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    time::{timeout, Instant},
+};
+
+// ICMP Echo 报文类型
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+// ICMP 头部长度与 Echo 负载长度
+const ICMP_HEADER_LEN: usize = 8;
+const ICMP_PAYLOAD_LEN: usize = 16;
+
+// 全局递增的 Echo 序号, 用于区分同时进行的多个探测
+static ECHO_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+// 每个出口绑定与地址族共享一个 ICMP 套接字, 首次使用时创建
+static PINGERS: Registry<IcmpPinger> = Registry::new();
+
+/// 对单个 IP 发送一次 ICMP Echo 请求, 返回探测记录。
+///
+/// 优先使用无需特权的 ICMP 数据报套接字 (Linux 需在 `net.ipv4.ping_group_range` 范围内),
+/// 不可用时回退到需要 root / CAP_NET_RAW 的原始套接字。同一出口绑定与地址族的探测共享一个套接字。
+pub async fn icmp_ping(ip: IpAddr, timeout_ms: i32, binding: &SocketBinding) -> ProbeResult {
+    let time_out = Duration::from_millis(timeout_ms as u64);
+    let started_at = SystemTime::now();
+    let result = match pinger(binding, ip.is_ipv4()) {
+        Ok(pinger) => pinger.probe(ip, time_out).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &result {
+        if e.kind() != io::ErrorKind::TimedOut {
+            debug!("IP {} ICMP 探测失败: {}", ip, e);
+        }
+    }
+    ProbeResult::new(
+        SocketAddr::new(ip, 0),
+        started_at,
+        ProbeOutcome::from_io(result),
+    )
+}
+
+/// 检测本机能否按出站绑定对指定地址族创建 ICMP 套接字。
+pub fn icmp_available(binding: &SocketBinding, is_ipv4: bool) -> bool {
+    match pinger(binding, is_ipv4) {
+        Ok(_) => true,
+        Err(e) => {
+            debug!(
+                "无法创建 {} ICMP 套接字: {}",
+                if is_ipv4 { "IPv4" } else { "IPv6" },
+                e
+            );
+            false
+        }
+    }
+}
+
+// 获取或创建指定绑定与地址族的 ICMP 套接字
+fn pinger(binding: &SocketBinding, is_ipv4: bool) -> io::Result<Arc<IcmpPinger>> {
+    PINGERS.get_or_open(binding, is_ipv4, || IcmpPinger::open(binding, is_ipv4))
+}
+
+// 等待应答的探测, 以对端地址与 Echo 序号区分
+type PendingKey = (IpAddr, u16);
+
+// 等待应答的探测所发送的负载, 以及收到应答时的通知
+type PendingEcho = ([u8; ICMP_PAYLOAD_LEN], oneshot::Sender<Instant>);
+
+// 同一出口绑定与地址族共享的 ICMP 套接字, 后台任务负责接收应答
+struct IcmpPinger {
+    socket: UdpSocket,
+    is_ipv4: bool,
+    raw: bool,
+    pending: Mutex<HashMap<PendingKey, PendingEcho>>,
+}
+
+impl IcmpPinger {
+    fn open(binding: &SocketBinding, is_ipv4: bool) -> io::Result<Arc<IcmpPinger>> {
+        let (socket, raw) = open_socket(is_ipv4, binding)?;
+        let pinger = Arc::new(IcmpPinger {
+            socket,
+            is_ipv4,
+            raw,
+            pending: Mutex::new(HashMap::new()),
+        });
+
+        // 套接字创建后常驻
+        pinger.clone().spawn_receiver();
+        Ok(pinger)
+    }
+
+    // 发送 Echo 请求并等待匹配的 Echo 应答
+    async fn probe(&self, ip: IpAddr, time_out: Duration) -> io::Result<Duration> {
+        let sequence = ECHO_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let identifier = std::process::id() as u16;
+        let token: [u8; ICMP_PAYLOAD_LEN] = rand::random();
+        let request = build_echo_request(self.is_ipv4, identifier, sequence, &token);
+
+        let (sender, receiver) = oneshot::channel();
+        let key: PendingKey = (ip, sequence);
+        self.pending.lock().unwrap().insert(key, (token, sender));
+
+        let start = Instant::now();
+        if let Err(e) = self.socket.send_to(&request, SocketAddr::new(ip, 0)).await {
+            self.pending.lock().unwrap().remove(&key);
+            return Err(e);
+        }
+
+        let reply = timeout(time_out, receiver).await;
+        self.pending.lock().unwrap().remove(&key);
+        match reply {
+            Ok(Ok(received)) => Ok(received.duration_since(start)),
+            Ok(Err(_)) | Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+impl PacketHandler for IcmpPinger {
+    const NAME: &'static str = "ICMP 套接字";
+
+    fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    // 解析收到的 ICMP 报文, 唤醒匹配的探测
+    fn handle_packet(&self, packet: &[u8], from: IpAddr, received: Instant) {
+        // IPv4 原始套接字收到的数据包含 IP 头部, 需要跳过
+        let packet = if self.is_ipv4 && self.raw {
+            skip_ipv4_header(packet)
+        } else {
+            Some(packet)
+        };
+        let Some((sequence, token)) =
+            packet.and_then(|packet| parse_echo_reply(self.is_ipv4, packet))
+        else {
+            return;
+        };
+
+        let mut pending = self.pending.lock().unwrap();
+        let key: PendingKey = (from, sequence);
+        if pending
+            .get(&key)
+            .is_some_and(|(expected, _)| *expected == token)
+        {
+            if let Some((_, sender)) = pending.remove(&key) {
+                let _ = sender.send(received);
+            }
+        }
+    }
+}
+
+// 创建 ICMP 套接字并应用绑定设置, 返回套接字以及是否为原始套接字
+fn open_socket(is_ipv4: bool, binding: &SocketBinding) -> io::Result<(UdpSocket, bool)> {
+    let (domain, protocol) = if is_ipv4 {
+        (Domain::IPV4, Protocol::ICMPV4)
+    } else {
+        (Domain::IPV6, Protocol::ICMPV6)
+    };
+
+    let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => (socket, false),
+        Err(_) => (Socket::new(domain, Type::RAW, Some(protocol))?, true),
+    };
+
+    binding.apply(&socket, &unspecified_address(is_ipv4))?;
+
+    socket.set_nonblocking(true)?;
+    let socket: std::net::UdpSocket = socket.into();
+    Ok((UdpSocket::from_std(socket)?, raw))
+}
+
+// 构建 ICMP Echo 请求报文
+fn build_echo_request(
+    is_ipv4: bool,
+    identifier: u16,
+    sequence: u16,
+    token: &[u8; ICMP_PAYLOAD_LEN],
+) -> Vec<u8> {
+    let mut packet = vec![0u8; ICMP_HEADER_LEN + ICMP_PAYLOAD_LEN];
+    packet[0] = if is_ipv4 {
+        ICMPV4_ECHO_REQUEST
+    } else {
+        ICMPV6_ECHO_REQUEST
+    };
+    packet[4..6].copy_from_slice(&identifier.to_be_bytes());
+    packet[6..8].copy_from_slice(&sequence.to_be_bytes());
+    packet[ICMP_HEADER_LEN..].copy_from_slice(token);
+
+    // ICMPv6 的校验和包含伪头部, 由内核计算
+    if is_ipv4 {
+        let checksum = internet_checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    packet
+}
+
+// 解析 Echo 应答报文, 返回其中的序号与负载
+// 数据报套接字的标识符会被内核改写, 因此只比较序号与负载
+fn parse_echo_reply(is_ipv4: bool, packet: &[u8]) -> Option<(u16, [u8; ICMP_PAYLOAD_LEN])> {
+    let reply_type = if is_ipv4 {
+        ICMPV4_ECHO_REPLY
+    } else {
+        ICMPV6_ECHO_REPLY
+    };
+
+    if packet.len() < ICMP_HEADER_LEN + ICMP_PAYLOAD_LEN || packet[0] != reply_type {
+        return None;
+    }
+    let sequence = u16::from_be_bytes([packet[6], packet[7]]);
+    let token = packet[ICMP_HEADER_LEN..ICMP_HEADER_LEN + ICMP_PAYLOAD_LEN]
+        .try_into()
+        .ok()?;
+    Some((sequence, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_request_checksum_verifies() {
+        let token = [7u8; ICMP_PAYLOAD_LEN];
+        let request = build_echo_request(true, 0x1234, 42, &token);
+        assert_eq!(request[0], ICMPV4_ECHO_REQUEST);
+        assert_eq!(request.len(), ICMP_HEADER_LEN + ICMP_PAYLOAD_LEN);
+        assert_eq!(internet_checksum(&request), 0);
+    }
+
+    #[test]
+    fn parse_echo_reply_matches_sequence_and_token() {
+        let token: [u8; ICMP_PAYLOAD_LEN] = core::array::from_fn(|i| i as u8);
+        let mut reply = build_echo_request(false, 1, 513, &token);
+        reply[0] = ICMPV6_ECHO_REPLY;
+        assert_eq!(parse_echo_reply(false, &reply), Some((513, token)));
+
+        // 请求报文、类型不符或长度不足的报文不是应答
+        assert_eq!(parse_echo_reply(true, &reply), None);
+        assert_eq!(parse_echo_reply(false, &reply[..ICMP_HEADER_LEN]), None);
+        let request = build_echo_request(false, 1, 513, &token);
+        assert_eq!(parse_echo_reply(false, &request), None);
+    }
+}
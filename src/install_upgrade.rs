@@ -43,8 +43,8 @@ pub fn install_systemd(args: Args) {
     }
 
     // 检查是否已存在相同名称的服务文件
-    match fs::metadata("/etc/systemd/system/cfst_slave.service") {
-        Ok(_) => loop {
+    if fs::metadata("/etc/systemd/system/cfst_slave.service").is_ok() {
+        loop {
             warn!("您的当前系统曾经配置过 Systemd 保活服务, 是否覆盖? (Y/N)");
             let mut input = String::new();
             io::stdin().read_line(&mut input).unwrap();
@@ -59,8 +59,7 @@ pub fn install_systemd(args: Args) {
             } else {
                 warn!("输入错误, 请重新输入 Y 或 N。");
             }
-        },
-        Err(_) => {}
+        }
     }
 
     // 复制可执行文件到 /usr/bin
@@ -381,7 +380,7 @@ pub async fn upgrade_bin(
     let mut command = Command::new(env::current_exe().unwrap());
     command.args(env::args().skip(1));
 
-    let _ = match command.spawn() {
+    match command.spawn() {
         Ok(_) => {
             info!("成功启动新程序");
             exit(1);
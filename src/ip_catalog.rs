@@ -0,0 +1,146 @@
+use crate::args::Args;
+
+use ipnetwork::IpNetwork;
+use log::{debug, info};
+use std::{error::Error, fs};
+
+// Cloudflare 公布的 IPv4 段 (https://www.cloudflare.com/ips-v4)
+const CLOUDFLARE_IPV4_RANGES: &[&str] = &[
+    "173.245.48.0/20",
+    "103.21.244.0/22",
+    "103.22.200.0/22",
+    "103.31.4.0/22",
+    "141.101.64.0/18",
+    "108.162.192.0/18",
+    "190.93.240.0/20",
+    "188.114.96.0/20",
+    "197.234.240.0/22",
+    "198.41.128.0/17",
+    "162.158.0.0/15",
+    "104.16.0.0/13",
+    "104.24.0.0/14",
+    "172.64.0.0/13",
+    "131.0.72.0/22",
+];
+
+// Cloudflare 公布的 IPv6 段 (https://www.cloudflare.com/ips-v6)
+const CLOUDFLARE_IPV6_RANGES: &[&str] = &[
+    "2400:cb00::/32",
+    "2606:4700::/32",
+    "2803:f800::/32",
+    "2405:b500::/32",
+    "2405:8100::/32",
+    "2a06:98c0::/29",
+    "2c0f:f248::/32",
+];
+
+// 主端下发任务时可用于引用目录 IP 段的关键字:
+// cloudflare 为全部 IP 段, cloudflare-v4 / cloudflare-v6 分别为仅 IPv4 / IPv6 段
+const CATALOG_KEYWORD_ALL: &str = "cloudflare";
+const CATALOG_KEYWORD_V4: &str = "cloudflare-v4";
+const CATALOG_KEYWORD_V6: &str = "cloudflare-v6";
+
+/// Cloudflare IP 段目录。
+///
+/// 默认使用编译进二进制的 Cloudflare 公布 IP 段, 也可以从本地文件刷新。
+/// 目录同时保存了 IPv4 / IPv6 的过滤设置, 该过滤同样作用于主端下发的 IP 段。
+/// 排除 / 允许列表由 `IpFilter` 负责。
+#[derive(Debug, Clone)]
+pub struct IpCatalog {
+    networks: Vec<IpNetwork>,
+    ipv4_only: bool,
+    ipv6_only: bool,
+}
+
+impl IpCatalog {
+    /// 根据命令行参数构建 IP 段目录。
+    ///
+    /// 若设置了 `--ip-ranges-file`, 则从该文件读取 IP 段, 否则使用内置 IP 段。
+    pub fn from_args(args: &Args) -> Result<IpCatalog, Box<dyn Error>> {
+        let networks = match &args.ip_ranges_file {
+            Some(path) => {
+                let networks = load_ranges_file(path)?;
+                info!("成功从 {} 加载 {} 个 IP 段", path, networks.len());
+                networks
+            }
+            None => builtin_ranges(),
+        };
+
+        let catalog = IpCatalog {
+            networks,
+            ipv4_only: args.ipv4_only,
+            ipv6_only: args.ipv6_only,
+        };
+        debug!("IP 段目录: {:?}", catalog.networks());
+
+        Ok(catalog)
+    }
+
+    /// 返回经过过滤后的目录 IP 段。
+    pub fn networks(&self) -> Vec<IpNetwork> {
+        self.filter(self.networks.clone())
+    }
+
+    /// 将主端下发的 IP 段 (或目录关键字) 展开为经过过滤的 IP 段列表。
+    pub fn resolve(&self, ip_cidr: &[String]) -> Result<Vec<IpNetwork>, Box<dyn Error>> {
+        let mut networks: Vec<IpNetwork> = Vec::new();
+
+        for cidr in ip_cidr {
+            match cidr.trim().to_lowercase().as_str() {
+                CATALOG_KEYWORD_ALL => networks.extend(self.networks.iter().cloned()),
+                CATALOG_KEYWORD_V4 => {
+                    networks.extend(self.networks.iter().filter(|net| net.is_ipv4()).cloned())
+                }
+                CATALOG_KEYWORD_V6 => {
+                    networks.extend(self.networks.iter().filter(|net| net.is_ipv6()).cloned())
+                }
+                other => networks.push(other.parse::<IpNetwork>()?),
+            }
+        }
+
+        Ok(self.filter(networks))
+    }
+
+    // 根据 IPv4 / IPv6 设置过滤 IP 段
+    fn filter(&self, networks: Vec<IpNetwork>) -> Vec<IpNetwork> {
+        networks
+            .into_iter()
+            .filter(|net| !(self.ipv4_only && net.is_ipv6()))
+            .filter(|net| !(self.ipv6_only && net.is_ipv4()))
+            .collect()
+    }
+}
+
+/// 返回内置的 Cloudflare IP 段。
+pub fn builtin_ranges() -> Vec<IpNetwork> {
+    CLOUDFLARE_IPV4_RANGES
+        .iter()
+        .chain(CLOUDFLARE_IPV6_RANGES.iter())
+        .map(|cidr| cidr.parse::<IpNetwork>().unwrap())
+        .collect()
+}
+
+/// 从本地文件读取 IP 段。
+///
+/// 文件格式与 Cloudflare 提供的 ips-v4 / ips-v6 相同: 每行一个 CIDR,
+/// 空行及以 `#` 开头的注释行会被忽略。
+pub fn load_ranges_file(path: &str) -> Result<Vec<IpNetwork>, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    let lines: Vec<String> = content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect();
+
+    parse_cidrs(&lines)
+}
+
+/// 将字符串列表解析为 IP 段列表。
+pub fn parse_cidrs(cidrs: &[String]) -> Result<Vec<IpNetwork>, Box<dyn Error>> {
+    let mut networks: Vec<IpNetwork> = Vec::new();
+    for cidr in cidrs {
+        networks.push(cidr.trim().parse::<IpNetwork>()?);
+    }
+    Ok(networks)
+}
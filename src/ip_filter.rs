@@ -0,0 +1,190 @@
+use crate::{
+    args::Args,
+    ip_catalog::{load_ranges_file, parse_cidrs},
+};
+
+use ipnetwork::IpNetwork;
+use log::info;
+use std::{error::Error, net::IpAddr};
+
+/// IP 段允许 / 排除列表。
+///
+/// 设置了允许列表时, 只保留落在允许列表内的地址; 随后从中去除排除列表覆盖的地址。
+/// 过滤以 IP 段为单位进行, 因此不会影响采样的均匀性, 也无需逐个检查地址。
+#[derive(Debug, Clone)]
+pub struct IpFilter {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+impl IpFilter {
+    /// 根据命令行参数构建允许 / 排除列表。
+    pub fn from_args(args: &Args) -> Result<IpFilter, Box<dyn Error>> {
+        let mut allow = parse_cidrs(&args.allow_prefix)?;
+        if let Some(path) = &args.allow_file {
+            let networks = load_ranges_file(path)?;
+            info!("成功从 {} 加载 {} 个允许的 IP 段", path, networks.len());
+            allow.extend(networks);
+        }
+
+        let mut deny = parse_cidrs(&args.exclude_prefix)?;
+        if let Some(path) = &args.exclude_file {
+            let networks = load_ranges_file(path)?;
+            info!("成功从 {} 加载 {} 个排除的 IP 段", path, networks.len());
+            deny.extend(networks);
+        }
+
+        Ok(IpFilter { allow, deny })
+    }
+
+    /// 在本地排除列表的基础上追加主端下发的排除 IP 段, 返回新的过滤器。
+    pub fn with_exclusions(&self, exclude_ranges: &[String]) -> Result<IpFilter, Box<dyn Error>> {
+        let mut filter = self.clone();
+        filter.deny.extend(parse_cidrs(exclude_ranges)?);
+        Ok(filter)
+    }
+
+    /// 对 IP 段应用允许 / 排除列表。
+    pub fn apply(&self, networks: Vec<IpNetwork>) -> Vec<IpNetwork> {
+        let mut filtered: Vec<IpNetwork> = if self.allow.is_empty() {
+            networks
+        } else {
+            networks
+                .into_iter()
+                .flat_map(|net| {
+                    self.allow
+                        .iter()
+                        .filter_map(move |allow| intersect_network(net, *allow))
+                })
+                .collect()
+        };
+
+        for exclude in &self.deny {
+            filtered = filtered
+                .into_iter()
+                .flat_map(|net| subtract_network(net, *exclude))
+                .collect();
+        }
+
+        filtered
+    }
+}
+
+/// 返回两个 IP 段的交集。
+///
+/// 两个 CIDR 要么互不重叠, 要么其中一个包含另一个, 因此交集为较小的那个或不存在。
+pub fn intersect_network(network: IpNetwork, other: IpNetwork) -> Option<IpNetwork> {
+    if network.is_ipv4() != other.is_ipv4() {
+        return None;
+    }
+
+    if other.prefix() <= network.prefix() {
+        if other.contains(network.network()) {
+            return Some(network);
+        }
+    } else if network.contains(other.network()) {
+        return Some(other);
+    }
+
+    None
+}
+
+/// 从 `network` 中去除 `exclude` 覆盖的部分, 返回剩余的 IP 段。
+///
+/// 若两者不重叠则原样返回; 若 `exclude` 完全覆盖 `network` 则返回空;
+/// 否则将 `network` 对半拆分后递归处理。
+pub fn subtract_network(network: IpNetwork, exclude: IpNetwork) -> Vec<IpNetwork> {
+    if network.is_ipv4() != exclude.is_ipv4() {
+        return vec![network];
+    }
+
+    if exclude.prefix() <= network.prefix() {
+        if exclude.contains(network.network()) {
+            return vec![];
+        }
+        return vec![network];
+    }
+
+    if !network.contains(exclude.network()) {
+        return vec![network];
+    }
+
+    let (low, high) = split_network(network);
+    let mut remaining = subtract_network(low, exclude);
+    remaining.extend(subtract_network(high, exclude));
+    remaining
+}
+
+// 将 IP 段对半拆分为两个前缀长度加一的 IP 段
+fn split_network(network: IpNetwork) -> (IpNetwork, IpNetwork) {
+    let prefix = network.prefix() + 1;
+    match network.network() {
+        IpAddr::V4(addr) => {
+            let base = u32::from(addr);
+            let half = 1u32 << (32 - prefix);
+            (
+                IpNetwork::new(IpAddr::V4(base.into()), prefix).unwrap(),
+                IpNetwork::new(IpAddr::V4((base + half).into()), prefix).unwrap(),
+            )
+        }
+        IpAddr::V6(addr) => {
+            let base = u128::from(addr);
+            let half = 1u128 << (128 - prefix);
+            (
+                IpNetwork::new(IpAddr::V6(base.into()), prefix).unwrap(),
+                IpNetwork::new(IpAddr::V6((base + half).into()), prefix).unwrap(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn nets(list: &[&str]) -> Vec<IpNetwork> {
+        list.iter().map(|s| net(s)).collect()
+    }
+
+    #[test]
+    fn subtract_disjoint_and_covering() {
+        assert_eq!(
+            subtract_network(net("104.16.0.0/24"), net("104.17.0.0/24")),
+            nets(&["104.16.0.0/24"])
+        );
+        assert_eq!(
+            subtract_network(net("104.16.0.0/24"), net("104.16.0.0/16")),
+            vec![]
+        );
+        assert_eq!(
+            subtract_network(net("104.16.0.0/24"), net("2606:4700::/32")),
+            nets(&["104.16.0.0/24"])
+        );
+    }
+
+    #[test]
+    fn subtract_inner_range() {
+        assert_eq!(
+            subtract_network(net("104.16.0.0/22"), net("104.16.1.0/24")),
+            nets(&["104.16.0.0/24", "104.16.2.0/23"])
+        );
+        assert_eq!(
+            subtract_network(net("2606:4700::/126"), net("2606:4700::3/128")),
+            nets(&["2606:4700::/127", "2606:4700::2/128"])
+        );
+    }
+
+    #[test]
+    fn subtract_keeps_every_other_address() {
+        let network = net("10.0.0.0/24");
+        let exclude = net("10.0.0.77/32");
+        let remaining = subtract_network(network, exclude);
+        let count: u32 = remaining.iter().map(|n| 1u32 << (32 - n.prefix())).sum();
+        assert_eq!(count, 255);
+        assert!(remaining.iter().all(|n| !n.contains(exclude.network())));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    args::Args,
+    probe::{ProbeOutcome, ProbeResult},
+};
+
+use log::{debug, info};
+use std::{future::Future, io, sync::Mutex, time::Duration};
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    time::{sleep_until, Instant},
+};
+
+// 自适应模式下每统计多少次探测评估一次超时率
+const ADAPTIVE_WINDOW: usize = 64;
+
+// 自适应模式下并发数的下限
+const ADAPTIVE_MIN_CONCURRENCY: usize = 4;
+
+// 超时率比基线高出该值时视为超时增多
+const TIMEOUT_SPIKE: f64 = 0.15;
+
+// 超时率基线的平滑系数
+const TIMEOUT_BASELINE_ALPHA: f64 = 0.2;
+
+// 因资源耗尽而降低并发后的冷却时间, 期间已发出的探测失败不再重复降低并发
+const EXHAUSTED_COOLDOWN: Duration = Duration::from_secs(1);
+
+// 带宽限制允许的突发时长
+const BANDWIDTH_BURST: Duration = Duration::from_millis(100);
+
+/// 探测并发与速率限制器。
+///
+/// 每次探测 (一次 TCP 连接、ICMP Echo 或 TLS 握手) 开始前须取得一个并发许可, 设置了
+/// 每秒探测上限时还须等待属于自己的发送时间。自适应模式下, 出现 EMFILE、ENOBUFS 等
+/// 资源耗尽错误或超时率明显上升时降低并发数, 情况好转后逐步恢复, 但不超过设置的并发上限。
+pub struct ProbeLimiter {
+    max_concurrency: usize,
+    adaptive: bool,
+    semaphore: Semaphore,
+    pacing_interval: Option<Duration>,
+    next_slot: Mutex<Instant>,
+    state: Mutex<AdaptiveState>,
+}
+
+// 自适应模式的统计状态
+struct AdaptiveState {
+    // 当前并发数
+    limit: usize,
+    // 尚在使用中、归还时需要回收的许可数量
+    debt: usize,
+    window_total: usize,
+    window_timeouts: usize,
+    baseline_timeout: Option<f64>,
+    last_backoff: Option<Instant>,
+}
+
+impl ProbeLimiter {
+    /// 根据命令行参数构建探测限制器。
+    pub fn from_args(args: &Args) -> ProbeLimiter {
+        let max_concurrency = args.probe_concurrency.max(1);
+        ProbeLimiter {
+            max_concurrency,
+            adaptive: args.probe_adaptive,
+            semaphore: Semaphore::new(max_concurrency),
+            pacing_interval: (args.probe_max_pps > 0)
+                .then(|| Duration::from_secs(1) / args.probe_max_pps),
+            next_slot: Mutex::new(Instant::now()),
+            state: Mutex::new(AdaptiveState {
+                limit: max_concurrency,
+                debt: 0,
+                window_total: 0,
+                window_timeouts: 0,
+                baseline_timeout: None,
+                last_backoff: None,
+            }),
+        }
+    }
+
+    /// 返回设置的并发上限。
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// 在并发与速率限制下执行一次探测, 自适应模式下会根据探测结果调整并发数。
+    pub async fn run<F>(&self, probe: F) -> ProbeResult
+    where
+        F: Future<Output = ProbeResult>,
+    {
+        // 许可在探测结束或被取消时归还
+        let _permit = ProbePermit {
+            limiter: self,
+            permit: Some(
+                self.semaphore
+                    .acquire()
+                    .await
+                    .expect("探测限制器的信号量不会被关闭"),
+            ),
+        };
+        self.wait_for_slot().await;
+
+        let result = probe.await;
+
+        if self.adaptive {
+            self.record(&result);
+        }
+        result
+    }
+
+    // 按每秒探测上限为本次探测分配发送时间并等待
+    async fn wait_for_slot(&self) {
+        let Some(interval) = self.pacing_interval else {
+            return;
+        };
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + interval;
+            slot
+        };
+        sleep_until(slot).await;
+    }
+
+    // 记录一次探测结果, 并在需要时调整并发数
+    fn record(&self, result: &ProbeResult) {
+        let mut state = self.state.lock().unwrap();
+
+        match &result.outcome {
+            ProbeOutcome::Error(e) if is_resource_exhausted(e) => {
+                let cooling = state
+                    .last_backoff
+                    .is_some_and(|last| last.elapsed() < EXHAUSTED_COOLDOWN);
+                if !cooling {
+                    let limit = state.limit / 2;
+                    self.set_limit(&mut state, limit, &format!("本机资源不足 ({})", e));
+                    state.last_backoff = Some(Instant::now());
+                }
+                return;
+            }
+            ProbeOutcome::Timeout => state.window_timeouts += 1,
+            _ => {}
+        }
+
+        state.window_total += 1;
+        if state.window_total < ADAPTIVE_WINDOW {
+            return;
+        }
+
+        let ratio = state.window_timeouts as f64 / state.window_total as f64;
+        state.window_total = 0;
+        state.window_timeouts = 0;
+
+        let baseline = state.baseline_timeout.unwrap_or(ratio);
+        state.baseline_timeout =
+            Some(baseline * (1.0 - TIMEOUT_BASELINE_ALPHA) + ratio * TIMEOUT_BASELINE_ALPHA);
+
+        if ratio > baseline + TIMEOUT_SPIKE {
+            let limit = state.limit * 3 / 4;
+            self.set_limit(
+                &mut state,
+                limit,
+                &format!(
+                    "超时率由 {:.0}% 升至 {:.0}%",
+                    baseline * 100.0,
+                    ratio * 100.0
+                ),
+            );
+        } else if state.limit < self.max_concurrency {
+            let limit = state.limit + (state.limit / 8).max(1);
+            self.set_limit(&mut state, limit, "探测情况良好");
+        }
+    }
+
+    // 调整并发数, 降低时优先回收空闲的许可, 其余在归还时回收
+    fn set_limit(&self, state: &mut AdaptiveState, limit: usize, reason: &str) {
+        let limit = limit.clamp(
+            ADAPTIVE_MIN_CONCURRENCY.min(self.max_concurrency),
+            self.max_concurrency,
+        );
+        if limit == state.limit {
+            return;
+        }
+
+        if limit < state.limit {
+            let reduce = state.limit - limit;
+            let forgotten = self.semaphore.forget_permits(reduce);
+            state.debt += reduce - forgotten;
+            info!("{}, 探测并发数由 {} 降低至 {}", reason, state.limit, limit);
+        } else {
+            let increase = limit - state.limit;
+            let repaid = increase.min(state.debt);
+            state.debt -= repaid;
+            self.semaphore.add_permits(increase - repaid);
+            debug!("{}, 探测并发数由 {} 提高至 {}", reason, state.limit, limit);
+        }
+        state.limit = limit;
+    }
+}
+
+// 探测期间持有的并发许可, 归还时若并发数已被降低则回收该许可
+struct ProbePermit<'a> {
+    limiter: &'a ProbeLimiter,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for ProbePermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if state.debt > 0 {
+            if let Some(permit) = self.permit.take() {
+                state.debt -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// 测速带宽限制器。
+///
+/// 所有测速连接共享节点的带宽上限, 每次读取数据后按读取的字节数占用相应的传输时间,
+/// 超出上限时推迟下一次读取, 由 TCP 流量控制使对端降低发送速度。
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bytes_per_sec: f64,
+    next_free: Mutex<Instant>,
+}
+
+impl BandwidthLimiter {
+    /// 根据命令行参数构建带宽限制器, 未开启限速或未设置带宽时返回 None。
+    pub fn from_args(args: &Args) -> Option<BandwidthLimiter> {
+        (args.speed_rate_limit && args.max_mbps > 0)
+            .then(|| BandwidthLimiter::new(args.max_mbps as f64))
+    }
+
+    /// 创建带宽上限为 `mbps` 的限制器。
+    pub fn new(mbps: f64) -> BandwidthLimiter {
+        BandwidthLimiter {
+            bytes_per_sec: mbps * 1_000_000.0 / 8.0,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// 占用传输 `bytes` 字节所需的时间, 超出带宽上限时等待。
+    pub async fn consume(&self, bytes: usize) {
+        let until = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let now = Instant::now();
+            // 空闲时最多积累一小段时间的额度, 允许短暂的突发
+            let earliest = now.checked_sub(BANDWIDTH_BURST).unwrap_or(now);
+            let start = (*next_free).max(earliest);
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec);
+            *next_free
+        };
+        sleep_until(until).await;
+    }
+}
+
+/// 判断探测错误是否由本机资源耗尽 (文件描述符、缓冲区、本地端口或内存不足) 引起。
+pub fn is_resource_exhausted(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::AddrNotAvailable | io::ErrorKind::OutOfMemory
+    ) || matches!(
+        error.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EADDRNOTAVAIL)
+    )
+}
@@ -1,20 +1,45 @@
 mod args;
+mod bind;
 mod cfst_rpc;
+mod hijack;
+mod http;
+mod icmp;
 mod install_upgrade;
+mod ip_catalog;
+mod ip_filter;
+mod limiter;
 mod ping;
+mod probe;
+mod raw;
+mod sampling;
+mod selection;
 mod server_comm;
 mod speed;
+mod syn;
+mod task;
+mod trace;
+mod traffic;
 
-use crate::{args::*, cfst_rpc::*, install_upgrade::*, ping::*, server_comm::*, speed::*};
+use crate::{
+    args::*, bind::*, cfst_rpc::*, hijack::*, install_upgrade::*, ip_catalog::*, ip_filter::*,
+    limiter::*, ping::*, sampling::*, selection::*, server_comm::*, speed::*, task::*, traffic::*,
+};
 
 use cloudflare_speedtest_client::CloudflareSpeedtestClient;
-use log::{debug, error, info, warn};
+use log::{error, info, warn};
 use rustls::crypto::aws_lc_rs;
 use simple_logger::init_with_level;
-use std::{process::exit, time::Duration};
-use tokio::time::timeout;
+use std::{
+    error::Error,
+    process::exit,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tonic::transport::Channel;
 
+// 流量配额用尽时, 拒绝任务后最长等待该时间即重新连接主端, 不长期持有会话
+const QUOTA_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
 #[tokio::main]
 async fn main() {
     // 初始化命令行参数
@@ -27,7 +52,7 @@ async fn main() {
         init_with_level(log::Level::Info).unwrap();
     }
 
-    if args.max_mbps == 114514 && args.install == false {
+    if args.max_mbps == 114514 && !args.install {
         error!("必须设置 Max Mbps 参数: -m / --max-mbps ");
         exit(1);
     }
@@ -38,13 +63,106 @@ async fn main() {
         exit(1);
     }
 
-    let _ = aws_lc_rs::default_provider().install_default().unwrap();
+    aws_lc_rs::default_provider().install_default().unwrap();
+
+    // 加载 Cloudflare IP 段目录
+    let catalog: IpCatalog = match IpCatalog::from_args(&args) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            error!("无法加载 IP 段目录: {}", e);
+            exit(1);
+        }
+    };
+    // 加载允许 / 排除列表
+    let ip_filter: IpFilter = match IpFilter::from_args(&args) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            error!("无法加载允许 / 排除列表: {}", e);
+            exit(1);
+        }
+    };
+    let sample_config: SampleConfig = SampleConfig::from_args(&args);
+    // 加载探测设置, 其中的出站绑定会被各出口覆盖
+    let ping_config: PingConfig = match PingConfig::from_args(&args) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            error!("无法加载探测与测速的绑定设置: {}", e);
+            exit(1);
+        }
+    };
+    // 加载出口列表, 未定义出口时只有一个使用 --bind-* 设置的默认出口
+    let egress_list: Vec<Egress> = match Egress::from_args(&args) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            error!("无法加载出口设置: {}", e);
+            exit(1);
+        }
+    };
+    // 加载与主端通信的出站绑定
+    let control_binding: SocketBinding = match SocketBinding::control_from_args(&args) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            error!("无法加载与主端通信的绑定设置: {}", e);
+            exit(1);
+        }
+    };
+    for egress in &egress_list {
+        if !egress.binding.is_default() {
+            info!("出口 {} 使用: {}", egress.label(), egress.binding);
+        }
+    }
+    if !control_binding.is_default() {
+        info!("与主端通信使用出口: {}", control_binding);
+    }
+    let hijack_config: HijackConfig = HijackConfig::from_args(&args);
+    let selection_config: SelectionConfig = SelectionConfig::from_args(&args);
+    let speed_config: SpeedConfig = SpeedConfig::from_args(&args);
+    // 探测限制器在各任务间共享, 自适应模式下的并发数会延续到后续任务
+    let probe_limiter: Arc<ProbeLimiter> = Arc::new(ProbeLimiter::from_args(&args));
+
+    // 加载流量统计, 重启后从文件恢复今日与本月已用的流量; 未设置配额与统计文件时不统计流量
+    match TrafficMeter::from_args(&args) {
+        Ok(Some(meter)) => {
+            meter.log_usage();
+            init_traffic(meter);
+            // 定期保存流量统计, 避免异常退出时丢失过多记录
+            tokio::spawn(async {
+                let mut ticker = tokio::time::interval(Duration::from_secs(60));
+                loop {
+                    ticker.tick().await;
+                    save_traffic();
+                }
+            });
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("无法加载流量统计: {}", e);
+            exit(1);
+        }
+    }
+
+    // 检测各出口的 IPv6 连通性, 不可达时该出口跳过 IPv6 段
+    let mut egresses: Vec<(Egress, bool)> = Vec::with_capacity(egress_list.len());
+    for egress in egress_list {
+        let ipv6_available: bool = check_ipv6_connectivity(&egress.binding).await;
+        if ipv6_available {
+            info!("出口 {} 具备 IPv6 连通性", egress.label());
+        } else if args.ipv6_only {
+            warn!(
+                "出口 {} 不具备 IPv6 连通性, 但设置了仅使用 IPv6 段, 将无法测试任何 IP",
+                egress.label()
+            );
+        } else {
+            warn!("出口 {} 不具备 IPv6 连通性, 将跳过 IPv6 段", egress.label());
+        }
+        egresses.push((egress, ipv6_available));
+    }
 
     // 主循环, 用于定期执行速度测试
     loop {
         // 初始化Cloudflare Speedtest客户端
         let client: CloudflareSpeedtestClient<Channel> =
-            match init_client(args.clone().server).await {
+            match init_client(args.clone().server, &control_binding).await {
                 Ok(tmp) => {
                     info!("成功初始化 Cloudflare Speedtest 客户端");
                     tmp
@@ -83,17 +201,17 @@ async fn main() {
         upgrade_bin(client.clone(), args.clone(), bootstrap_res.clone()).await;
 
         loop {
-            // 发送速度测试请求, 获取测试结果和需要ping的IP列表
-            let (speedtest_response, need_ping_ips) = match send_speedtest(
+            // 发送速度测试请求, 获取测试任务
+            let speedtest_response = match send_speedtest(
                 client.clone(),
                 node_id.clone(),
                 session_token.clone(),
             )
             .await
             {
-                Ok((res, str)) => {
+                Ok(res) => {
                     info!("成功获取 Speedtest 信息, 开始启动测速程序");
-                    (res, str)
+                    res
                 }
                 Err(e) => {
                     error!("未能成功获取需要测试的 IP, 正在重新连接服务器: {}", e);
@@ -101,55 +219,100 @@ async fn main() {
                 }
             };
 
-            // 对需要ping的IP进行ping测试, 记录延迟
-            let mut ips_ping: std::collections::HashMap<String, u128> =
-                ping_ips(need_ping_ips, speedtest_response.maximum_ping).await;
-            info!("获取到 {} 个 IP, 开始测试", ips_ping.len());
-            // 移除延迟过高的IP
-            ips_ping.retain(|_, &mut value| value != u128::MAX);
-            info!("符合条件 IP 有 {} 个", ips_ping.len());
-            debug!("符合条件 IP: {:?}", ips_ping);
-
-            // 测试每个IP的速度, 选择最快且符合最小速度要求的IP
-            let mut the_last_ip: String = String::new();
-            let mut the_last_ip_ping: i32 = -1;
-            let mut the_last_ip_speed: i32 = -1;
-
-            for (speed_ip, ping) in ips_ping.clone() {
-                let tmp_speed = match timeout(
-                    Duration::from_secs(12),
-                    speed_one_ip(speedtest_response.speed_url.clone(), speed_ip.clone(), 10),
+            // 流量配额用尽时拒绝任务并告知主端原因, 等待统计周期结束或一段时间后重新连接主端
+            if let Some(exceeded) = traffic_exceeded() {
+                warn!("{}, 拒绝本次任务", exceeded);
+                match send_speedtest_result(
+                    Vec::new(),
+                    client.clone(),
+                    node_id.clone(),
+                    session_token.clone(),
+                    exceeded.to_string(),
                 )
                 .await
                 {
+                    Ok(_) => info!("已告知主端拒绝本次任务"),
+                    Err(e) => error!("无法告知主端拒绝本次任务: {}", e),
+                }
+                save_traffic();
+                let wait: Duration = (exceeded
+                    .resets_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    + Duration::from_secs(1))
+                .min(QUOTA_RETRY_INTERVAL);
+                info!("{}sec 后重新连接服务器", wait.as_secs());
+                tokio::time::sleep(wait).await;
+                break;
+            }
+
+            // 合并主端下发的排除列表
+            let task_filter: IpFilter =
+                match ip_filter.with_exclusions(&speedtest_response.exclude_ranges) {
                     Ok(tmp) => tmp,
                     Err(e) => {
-                        error!("IP {} 测速超时: {}", speed_ip, e);
-                        continue;
+                        error!("未能成功解析主端下发的排除列表, 正在重新连接服务器: {}", e);
+                        break;
                     }
                 };
-                if tmp_speed.round() as i32 >= speedtest_response.minimum_mbps {
-                    the_last_ip = speed_ip;
-                    the_last_ip_ping = ping as i32;
-                    the_last_ip_speed = tmp_speed.round() as i32;
-                    break;
-                } else {
-                    continue;
+
+            // 根据任务确定探测方式, 各出口无法使用 ICMP 或 SYN 的地址族在测速时回退到 TCP
+            let task_ping_config: PingConfig = ping_config.for_task(&speedtest_response);
+
+            // 未设置采样种子时为本次任务生成一个, 使各出口测试相同的候选 IP
+            let task_sample_config = SampleConfig {
+                seed: Some(sample_config.seed.unwrap_or_else(rand::random)),
+                ..sample_config.clone()
+            };
+
+            // 根据任务确定上报 IP 的选择策略
+            let task_selection_config: SelectionConfig =
+                selection_config.for_task(&speedtest_response);
+
+            let runner = TaskRunner {
+                catalog: &catalog,
+                ip_filter: &task_filter,
+                sample_config: &task_sample_config,
+                ping_config: &task_ping_config,
+                hijack_config: &hijack_config,
+                selection_config: &task_selection_config,
+                speed_config: &speed_config,
+                limiter: &probe_limiter,
+            };
+
+            // 依次在每个出口上测速
+            let mut ip_results: Vec<IpResult> = Vec::with_capacity(egresses.len());
+            let mut task_error: Option<Box<dyn Error>> = None;
+            for (egress, ipv6_available) in &egresses {
+                match runner
+                    .run(&speedtest_response, egress, *ipv6_available)
+                    .await
+                {
+                    Ok(tmp) => ip_results.push(tmp),
+                    Err(e) => {
+                        task_error = Some(e);
+                        break;
+                    }
                 }
             }
+            if let Some(e) = task_error {
+                error!("未能成功解析需要测试的 IP, 正在重新连接服务器: {}", e);
+                break;
+            }
 
-            if the_last_ip.is_empty() {
-                warn!("在测试完所有的 IP 后, 没有发现符合条件的 IP, 请检查您的网络环境, 或请求主端提供者降低最小带宽要求与 Ping 要求");
+            // 记录并保存本次任务后的已用流量
+            if let Some(meter) = traffic() {
+                meter.log_usage();
             }
+            save_traffic();
 
             // 发送速度测试结果
             match send_speedtest_result(
-                the_last_ip,
-                the_last_ip_ping,
-                the_last_ip_speed,
+                ip_results,
                 client.clone(),
                 node_id.clone(),
                 session_token.clone(),
+                String::new(),
             )
             .await
             {
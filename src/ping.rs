@@ -1,70 +1,877 @@
-use futures::{stream::iter, StreamExt};
+use crate::{
+    args::Args,
+    bind::SocketBinding,
+    cfst_rpc::SpeedtestResponse,
+    icmp::{icmp_available, icmp_ping},
+    ip_catalog::IpCatalog,
+    ip_filter::IpFilter,
+    limiter::ProbeLimiter,
+    probe::{summarize_failures, ProbeOutcome, ProbeResult},
+    sampling::*,
+    syn::{syn_available, syn_ping},
+    trace::{tls_probe, TlsTiming, DEFAULT_PROBE_SNI},
+    traffic::{record_traffic, traffic_exceeded},
+};
+
+use clap::ValueEnum;
+
+use futures::{
+    future::join_all,
+    stream::{iter, once, BoxStream},
+    StreamExt,
+};
 use ipnetwork::IpNetwork;
-use log::debug;
-use std::{collections::HashMap, error::Error, time::Duration};
-use tokio::io::AsyncWriteExt;
-use tokio::{
-    net::TcpStream,
-    sync::Mutex,
-    time::{timeout, Instant},
+use log::{debug, info, warn};
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    error::Error,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+use url::Url;
+
+// 检测 IPv6 连通性时连接的地址 (Cloudflare DNS 2606:4700:4700::1111)
+const IPV6_PROBE_ADDRESS: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111);
+
+// Cloudflare 代理的 HTTP / HTTPS 端口, 探测默认分别使用 80 与 443
+pub const CLOUDFLARE_HTTP_PORTS: &[u16] = &[80, 8080, 8880, 2052, 2082, 2086, 2095];
+pub const CLOUDFLARE_HTTPS_PORTS: &[u16] = &[443, 2053, 2083, 2087, 2096, 8443];
+
+// 一次 TLS 握手收发的数据量估算, 主要为服务端证书链
+const TLS_HANDSHAKE_BYTES: u64 = 6000;
+
+// 一次 /cdn-cgi/trace 请求与响应的数据量估算
+const TRACE_BYTES: u64 = 1000;
 
-async fn ping_single_ip(ip: String, timeout_ms: i32) -> i32 {
-    let addr = format!("{}:80", ip);
+// 整体排序时最多保留的候选 IP 数量, 只保留延迟统计最好的部分, 避免超大任务占用过多内存
+const FULL_ORDER_LIMIT: usize = 10000;
+
+// 对单个 IP 的指定端口进行一次 TCP 连接, 返回探测记录
+async fn ping_single_ip(
+    ip: IpAddr,
+    port: u16,
+    timeout_ms: i32,
+    binding: &SocketBinding,
+) -> ProbeResult {
+    let addr = SocketAddr::new(ip, port);
     let time_out = Duration::from_millis(timeout_ms as u64);
+    let started_at = SystemTime::now();
     let start = Instant::now();
-    match timeout(time_out, TcpStream::connect(&addr)).await {
-        Ok(tmp) => match tmp {
-            Ok(mut tcpstream) => {
-                let duration = start.elapsed().as_millis() as i32;
-                tcpstream.shutdown().await.unwrap();
-                drop(tcpstream);
-                if duration <= 10 {
-                    -1
-                } else {
-                    duration
-                }
+    let result = match timeout(time_out, binding.connect(addr)).await {
+        Ok(Ok(mut tcpstream)) => {
+            let duration = start.elapsed();
+            let _ = tcpstream.shutdown().await;
+            drop(tcpstream);
+            // 过低的延迟不再直接丢弃, 由劫持检测负责标记
+            Ok(duration)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::ErrorKind::TimedOut.into()),
+    };
+    ProbeResult::new(addr, started_at, ProbeOutcome::from_io(result))
+}
+
+/// 延迟探测方式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProbeMode {
+    /// TCP 连接, 默认 80 端口
+    Tcp,
+    /// ICMP Echo
+    Icmp,
+    /// TCP 连接并完成 TLS 握手, 默认 443 端口, 可选请求 /cdn-cgi/trace
+    Tls,
+    /// 原始套接字发送 SYN 并等待 SYN-ACK, 默认 80 端口, 需要 root 或 CAP_NET_RAW
+    Syn,
+}
+
+impl ProbeMode {
+    /// 返回探测方式的名称, 与主端下发及上报时使用的名称一致。
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeMode::Tcp => "tcp",
+            ProbeMode::Icmp => "icmp",
+            ProbeMode::Tls => "tls",
+            ProbeMode::Syn => "syn",
+        }
+    }
+
+    /// 解析主端下发的探测方式名称。
+    pub fn parse(name: &str) -> Option<ProbeMode> {
+        match name.trim().to_lowercase().as_str() {
+            "tcp" => Some(ProbeMode::Tcp),
+            "icmp" => Some(ProbeMode::Icmp),
+            "tls" => Some(ProbeMode::Tls),
+            "syn" => Some(ProbeMode::Syn),
+            _ => None,
+        }
+    }
+
+    /// 估算对 `ip` 进行一次探测 (含收发) 使用的流量, 用于流量统计。
+    pub fn probe_bytes(&self, ip: IpAddr, trace: bool) -> u64 {
+        let ip_header: u64 = if ip.is_ipv4() { 20 } else { 40 };
+        match self {
+            // 三次握手与四次挥手, TCP 头部含选项
+            ProbeMode::Tcp => 7 * (ip_header + 40),
+            // SYN、SYN-ACK 与 RST
+            ProbeMode::Syn => 3 * (ip_header + 32),
+            // Echo 请求与应答, 各带 16 字节数据
+            ProbeMode::Icmp => 2 * (ip_header + 8 + 16),
+            ProbeMode::Tls => {
+                let packets = if trace { 14 } else { 10 };
+                TLS_HANDSHAKE_BYTES
+                    + if trace { TRACE_BYTES } else { 0 }
+                    + packets * (ip_header + 32)
             }
-            Err(_) => -1,
-        },
-        Err(_) => -1,
-    }
-}
-
-pub async fn ping_ips(ips: Vec<String>, maximum_ping: i32) -> HashMap<String, u128> {
-    let ip_and_ping_map = std::sync::Arc::new(Mutex::new(HashMap::new()));
-    iter(ips)
-        .for_each_concurrent(Some(100), |ip| {
-            let clone_map = ip_and_ping_map.clone();
-            async move {
-                let duration = ping_single_ip(ip.clone(), maximum_ping).await;
-                if duration != -1 {
-                    debug!("IP {} Ping {}ms", ip, duration);
-                    let mut map_lock = clone_map.lock().await;
-                    map_lock.insert(ip, duration as u128);
-                } else {
-                    debug!("IP {} 不可达", ip);
-                    let mut map_lock = clone_map.lock().await;
-                    map_lock.insert(ip, u128::MAX);
-                }
+        }
+    }
+}
+
+/// 候选 IP 交给测速的顺序。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CandidateOrder {
+    /// 按探测完成的先后顺序, 最早开始测速; 顺序随探测耗时变化
+    Arrival,
+    /// 同一批完成探测的 IP 按延迟统计排序, 兼顾速度与质量; 分批取决于探测完成的时间, 顺序不确定
+    Batch,
+    /// 等待所有 IP 探测完成后按延迟统计整体排序, 唯一顺序完全确定的方式, 只保留最好的一部分
+    Full,
+}
+
+/// Ping 测试设置。
+#[derive(Debug, Clone)]
+pub struct PingConfig {
+    /// 探测方式
+    pub mode: ProbeMode,
+    /// 本机无法对 IPv4 地址使用设置的探测方式, 回退到 TCP 连接探测
+    pub fallback_v4: bool,
+    /// 本机无法对 IPv6 地址使用设置的探测方式, 回退到 TCP 连接探测
+    pub fallback_v6: bool,
+    /// 每个 IP 的探测次数
+    pub count: u32,
+    /// 同一 IP 两次探测之间的间隔
+    pub interval: Duration,
+    /// 丢包率上限 (百分比), 超过时该 IP 不参与测速
+    pub max_loss: f64,
+    /// TLS 探测使用的 SNI, 默认取任务测速 URL 的域名
+    pub sni: String,
+    /// TLS 探测时是否请求 /cdn-cgi/trace
+    pub trace: bool,
+    /// 探测的端口列表, 为空时使用探测方式的默认端口
+    pub ports: Vec<u16>,
+    /// 探测与测速使用的出站绑定设置
+    pub binding: SocketBinding,
+    /// 候选 IP 交给测速的顺序
+    pub order: CandidateOrder,
+}
+
+impl PingConfig {
+    /// 根据命令行参数构建 Ping 测试设置, 绑定设置无效时返回错误。
+    pub fn from_args(args: &Args) -> Result<PingConfig, Box<dyn Error>> {
+        Ok(PingConfig {
+            mode: args.probe_mode,
+            fallback_v4: false,
+            fallback_v6: false,
+            count: args.ping_count.max(1),
+            interval: Duration::from_millis(args.ping_interval_ms),
+            max_loss: args.ping_max_loss,
+            sni: DEFAULT_PROBE_SNI.to_string(),
+            trace: args.probe_trace,
+            ports: args.probe_ports.clone(),
+            binding: SocketBinding::probe_from_args(args)?,
+            order: args.candidate_order,
+        })
+    }
+
+    /// 返回对 `ip` 实际使用的探测方式, 该地址族回退时为 TCP 连接探测。
+    pub fn mode_for(&self, ip: IpAddr) -> ProbeMode {
+        let fallback = if ip.is_ipv4() {
+            self.fallback_v4
+        } else {
+            self.fallback_v6
+        };
+        if fallback {
+            ProbeMode::Tcp
+        } else {
+            self.mode
+        }
+    }
+
+    /// 按出站绑定检查各地址族能否使用 ICMP 或 SYN 探测, 无法使用的地址族回退到 TCP 连接探测。
+    ///
+    /// 只在具备 IPv6 连通性时检查 IPv6, 否则 IPv6 段会被跳过。
+    pub fn check_fallback(&mut self, label: &str, ipv6_available: bool) {
+        let (available, requirement): (fn(&SocketBinding, bool) -> bool, &str) = match self.mode {
+            ProbeMode::Icmp => (icmp_available, "需要 ping_group_range 权限或 CAP_NET_RAW"),
+            ProbeMode::Syn => (syn_available, "需要 root 或 CAP_NET_RAW"),
+            _ => return,
+        };
+        self.fallback_v4 = !available(&self.binding, true);
+        self.fallback_v6 = ipv6_available && !available(&self.binding, false);
+        for (fallback, family) in [(self.fallback_v4, "IPv4"), (self.fallback_v6, "IPv6")] {
+            if fallback {
+                warn!(
+                    "出口 {} 无法对 {} 地址使用 {} 探测 ({}, 或出站绑定无法应用), 本次任务回退到 TCP 连接探测",
+                    label,
+                    family,
+                    self.mode.as_str(),
+                    requirement
+                );
             }
+        }
+    }
+
+    /// 返回对 `ip` 探测时实际使用的端口列表, ICMP 探测不区分端口, 返回 `[0]`。
+    pub fn probe_ports(&self, ip: IpAddr) -> Vec<u16> {
+        match self.mode_for(ip) {
+            ProbeMode::Icmp => vec![0],
+            _ if !self.ports.is_empty() => self.ports.clone(),
+            ProbeMode::Tcp | ProbeMode::Syn => vec![CLOUDFLARE_HTTP_PORTS[0]],
+            ProbeMode::Tls => vec![CLOUDFLARE_HTTPS_PORTS[0]],
+        }
+    }
+
+    /// 根据主端下发的任务覆盖本地设置, 返回本次任务使用的设置。
+    pub fn for_task(&self, speedtest_response: &SpeedtestResponse) -> PingConfig {
+        let mut config = self.clone();
+        if !speedtest_response.probe_mode.is_empty() {
+            match ProbeMode::parse(&speedtest_response.probe_mode) {
+                Some(mode) => config.mode = mode,
+                None => warn!(
+                    "主端下发了未知的探测方式 {}, 使用本地设置 {}",
+                    speedtest_response.probe_mode,
+                    config.mode.as_str()
+                ),
+            }
+        }
+        if let Some(domain) = Url::parse(&speedtest_response.speed_url)
+            .ok()
+            .and_then(|url| url.domain().map(|domain| domain.to_string()))
+        {
+            config.sni = domain;
+        }
+        config.trace |= speedtest_response.probe_trace;
+        if !speedtest_response.probe_ports.is_empty() {
+            config.ports = speedtest_response
+                .probe_ports
+                .iter()
+                .filter_map(|&port| u16::try_from(port).ok())
+                .collect();
+        }
+        config
+    }
+}
+
+/// 单个 IP 多次探测得到的延迟统计, 延迟单位为毫秒。
+#[derive(Debug, Clone)]
+pub struct PingStats {
+    /// 最低延迟
+    pub min: u128,
+    /// 延迟中位数
+    pub median: u128,
+    /// 延迟 95 分位数
+    pub p95: u128,
+    /// 抖动, 即相邻两次成功探测的延迟差的平均值
+    pub jitter: f64,
+    /// 丢包率 (百分比)
+    pub loss: f64,
+    /// TLS 探测各阶段耗时的中位数, 仅 TLS 探测时存在
+    pub tls: Option<TlsTiming>,
+    /// 统计所属的端口, 即该 IP 表现最好的端口; ICMP 探测时为 0
+    pub port: u16,
+    /// 各探测端口的延迟中位数
+    pub ports: Vec<PortLatency>,
+    /// 所有端口的每次探测记录
+    pub results: Vec<ProbeResult>,
+}
+
+/// 单个端口的探测结果。
+#[derive(Debug, Clone)]
+pub struct PortLatency {
+    /// 端口
+    pub port: u16,
+    /// 延迟中位数, 该端口无应答时为 None
+    pub latency: Option<u128>,
+}
+
+impl PingStats {
+    /// 根据同一端口的多次探测记录计算延迟统计, 全部失败时返回 None。
+    pub fn from_results(results: &[ProbeResult]) -> Option<PingStats> {
+        let received: Vec<u128> = results
+            .iter()
+            .filter_map(|result| result.latency())
+            .map(|latency| latency.as_millis())
+            .collect();
+        if received.is_empty() {
+            return None;
+        }
+
+        let jitter = if received.len() > 1 {
+            received
+                .windows(2)
+                .map(|pair| pair[0].abs_diff(pair[1]) as f64)
+                .sum::<f64>()
+                / (received.len() - 1) as f64
+        } else {
+            0.0
+        };
+
+        let mut sorted = received.clone();
+        sorted.sort_unstable();
+
+        let tls_timings: Vec<TlsTiming> = results
+            .iter()
+            .filter_map(|result| result.tls.clone())
+            .collect();
+
+        Some(PingStats {
+            min: sorted[0],
+            median: percentile(&sorted, 50),
+            p95: percentile(&sorted, 95),
+            jitter,
+            loss: (results.len() - received.len()) as f64 * 100.0 / results.len() as f64,
+            tls: TlsTiming::median(&tls_timings),
+            port: results[0].target.port(),
+            ports: Vec::new(),
+            results: results.to_vec(),
         })
-        .await;
-    let mut inner_map = ip_and_ping_map.lock().await;
-    std::mem::take(&mut *inner_map)
+    }
+
+    /// 比较两个延迟统计的优劣, 依次比较延迟中位数、抖动、丢包率与 95 分位数, 越小越好。
+    pub fn rank_cmp(&self, other: &PingStats) -> Ordering {
+        self.median
+            .cmp(&other.median)
+            .then(self.jitter.total_cmp(&other.jitter))
+            .then(self.loss.total_cmp(&other.loss))
+            .then(self.p95.cmp(&other.p95))
+    }
 }
 
-pub async fn ip_cidr_to_ips(ip_cidr: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
-    let ip_cidr_string: Vec<String> = ip_cidr.into_iter().map(|fs| fs.to_string()).collect();
+// 最近秩法计算分位数, sorted 须已升序排列且非空
+fn percentile(sorted: &[u128], percent: usize) -> u128 {
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
 
-    let mut ip_addresses: Vec<String> = Vec::new();
+/// 对单个 IP 的单个端口按设置的探测方式、次数与间隔多次探测, 返回每次探测的记录。
+///
+/// 每次探测均受限制器的并发与速率限制。
+pub async fn ping_multiple(
+    ip: IpAddr,
+    port: u16,
+    timeout_ms: i32,
+    config: &PingConfig,
+    limiter: &ProbeLimiter,
+) -> Vec<ProbeResult> {
+    let mut results: Vec<ProbeResult> = Vec::with_capacity(config.count as usize);
+    for index in 0..config.count {
+        if index > 0 {
+            sleep(config.interval).await;
+        }
+        let mode = config.mode_for(ip);
+        let result = match mode {
+            ProbeMode::Tcp => {
+                limiter
+                    .run(ping_single_ip(ip, port, timeout_ms, &config.binding))
+                    .await
+            }
+            ProbeMode::Icmp => {
+                limiter
+                    .run(icmp_ping(ip, timeout_ms, &config.binding))
+                    .await
+            }
+            ProbeMode::Syn => {
+                limiter
+                    .run(syn_ping(ip, port, timeout_ms, &config.binding))
+                    .await
+            }
+            ProbeMode::Tls => {
+                limiter
+                    .run(tls_probe(
+                        ip,
+                        port,
+                        &config.sni,
+                        config.trace,
+                        timeout_ms,
+                        &config.binding,
+                    ))
+                    .await
+            }
+        };
+        record_traffic(mode.probe_bytes(ip, config.trace));
+        results.push(result);
+    }
+    results
+}
+
+/// 对单个 IP 的所有探测端口并行探测, 返回表现最好的端口的延迟统计, 并附带各端口的结果;
+/// 所有端口均无应答时返回全部探测记录, 以便说明失败原因。
+pub async fn probe_ports(
+    ip: IpAddr,
+    timeout_ms: i32,
+    config: &PingConfig,
+    limiter: &ProbeLimiter,
+) -> Result<PingStats, Vec<ProbeResult>> {
+    let ports = config.probe_ports(ip);
+    let results: Vec<Vec<ProbeResult>> = join_all(
+        ports
+            .iter()
+            .map(|&port| ping_multiple(ip, port, timeout_ms, config, limiter)),
+    )
+    .await;
 
-    for ips in ip_cidr_string {
-        let network = ips.parse::<IpNetwork>()?;
-        for single_ip in network.iter() {
-            ip_addresses.push(single_ip.to_string());
+    let port_stats: Vec<Option<PingStats>> = results
+        .iter()
+        .map(|results| PingStats::from_results(results))
+        .collect();
+    let port_latencies: Vec<PortLatency> = ports
+        .iter()
+        .zip(port_stats.iter())
+        .map(|(&port, stats)| PortLatency {
+            port,
+            latency: stats.as_ref().map(|stats| stats.median),
+        })
+        .collect();
+    let all_results: Vec<ProbeResult> = results.into_iter().flatten().collect();
+
+    match port_stats
+        .into_iter()
+        .flatten()
+        .min_by(|a, b| a.rank_cmp(b))
+    {
+        Some(mut best) => {
+            best.ports = port_latencies;
+            best.results = all_results;
+            Ok(best)
         }
+        None => Err(all_results),
     }
+}
 
-    Ok(ip_addresses)
+/// 以有限并发对惰性生成的 IP 逐个进行多次 Ping 测试, 返回由可达 IP 及其延迟统计组成的流。
+///
+/// 探测在独立的任务中进行, 下游测速期间探测照常进行, 延迟不会因下游未及时拉取而偏高;
+/// 同时测试的 IP 数量不超过限制器的并发上限, 每次探测另受限制器的并发与速率限制。
+/// 已得出结果但下游尚未拉取的 IP 至多缓存并发上限个, 缓存满时不再开始新的测试,
+/// 因此内存占用与任务大小无关; 流被丢弃时尚未完成的测试会被取消。
+/// 流量配额用尽后不再测试新的 IP, 已开始的测试照常完成。
+/// 延迟中位数超过 `maximum_ping` 或丢包率超过上限的 IP 不会交给下游。
+/// 可达 IP 交给下游的顺序由设置的 `CandidateOrder` 决定: 按完成顺序、按批排序或全部
+/// 完成后整体排序 (此时需等待所有 IP 探测完成, 只保留延迟统计最好的 `FULL_ORDER_LIMIT` 个),
+/// 不可达的 IP 会在日志中记录失败原因。
+pub fn ping_ips<I>(
+    ips: I,
+    maximum_ping: i32,
+    config: &PingConfig,
+    limiter: &Arc<ProbeLimiter>,
+) -> BoxStream<'static, (IpAddr, PingStats)>
+where
+    I: Iterator<Item = IpAddr> + Send + 'static,
+{
+    let order = config.order;
+    let concurrency = limiter.max_concurrency();
+    let (sender, receiver) = mpsc::channel(concurrency);
+    tokio::spawn(probe_all(
+        ips,
+        maximum_ping,
+        config.clone(),
+        limiter.clone(),
+        sender,
+    ));
+    let reachable = ReceiverStream::new(receiver);
+
+    match order {
+        CandidateOrder::Arrival => reachable.boxed(),
+        CandidateOrder::Batch => reachable
+            .ready_chunks(concurrency)
+            .flat_map(|mut chunk| {
+                chunk.sort_by(candidate_cmp);
+                iter(chunk)
+            })
+            .boxed(),
+        CandidateOrder::Full => once(reachable.fold(
+            BestCandidates::new(FULL_ORDER_LIMIT),
+            |mut best, candidate| async move {
+                best.push(candidate);
+                best
+            },
+        ))
+        .flat_map(|best| {
+            let count = best.count;
+            let candidates = best.into_sorted_vec();
+            info!(
+                "共 {} 个 IP 符合延迟要求, 已按延迟统计排序并保留最好的 {} 个",
+                count,
+                candidates.len()
+            );
+            iter(candidates)
+        })
+        .boxed(),
+    }
+}
+
+// 以有限并发探测所有 IP, 将符合要求的 IP 发送给下游, 下游关闭后停止探测
+//
+// 每个 IP 的探测在独立的任务中进行, 等待下游拉取时已开始的探测照常计时;
+// 本函数返回时会取消尚未完成的探测。
+async fn probe_all<I>(
+    ips: I,
+    maximum_ping: i32,
+    config: PingConfig,
+    limiter: Arc<ProbeLimiter>,
+    sender: mpsc::Sender<(IpAddr, PingStats)>,
+) where
+    I: Iterator<Item = IpAddr>,
+{
+    let concurrency = limiter.max_concurrency();
+    let mut ips = ips.take_while(|_| traffic_exceeded().is_none());
+    let mut probes = JoinSet::new();
+    loop {
+        while probes.len() < concurrency {
+            let Some(ip) = ips.next() else {
+                break;
+            };
+            let config = config.clone();
+            let limiter = limiter.clone();
+            probes.spawn(async move {
+                let stats = probe_ports(ip, maximum_ping, &config, &limiter).await;
+                (ip, stats)
+            });
+        }
+
+        let joined = tokio::select! {
+            joined = probes.join_next() => joined,
+            _ = sender.closed() => return,
+        };
+        let Some(joined) = joined else {
+            return;
+        };
+        let Ok((ip, stats)) = joined else {
+            continue;
+        };
+        if let Some(candidate) = qualify(ip, stats, maximum_ping, config.max_loss) {
+            if sender.send(candidate).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// 记录单个 IP 的探测结果, 返回符合延迟与丢包率要求的候选 IP
+fn qualify(
+    ip: IpAddr,
+    stats: Result<PingStats, Vec<ProbeResult>>,
+    maximum_ping: i32,
+    max_loss: f64,
+) -> Option<(IpAddr, PingStats)> {
+    match stats {
+        Ok(stats) => {
+            debug!(
+                "IP {} 端口 {} Ping 最低 {}ms, 中位数 {}ms, P95 {}ms, 抖动 {:.1}ms, 丢包率 {:.0}%",
+                ip, stats.port, stats.min, stats.median, stats.p95, stats.jitter, stats.loss
+            );
+            if stats.ports.len() > 1 {
+                debug!("IP {} 各端口延迟: {:?}", ip, stats.ports);
+            }
+            if let Some(tls) = &stats.tls {
+                debug!(
+                    "IP {} TCP 连接 {}ms, TLS 握手 {}ms, 首字节 {:?}ms, 数据中心 {:?}",
+                    ip, tls.connect, tls.handshake, tls.ttfb, tls.colo
+                );
+            }
+            if stats.loss > 0.0 {
+                debug!(
+                    "IP {} 部分探测失败: {}",
+                    ip,
+                    summarize_failures(&stats.results).join(", ")
+                );
+            }
+            if stats.median > maximum_ping as u128 {
+                debug!(
+                    "IP {} 延迟中位数 {}ms 超过上限 {}ms, 跳过",
+                    ip, stats.median, maximum_ping
+                );
+                return None;
+            }
+            if stats.loss > max_loss {
+                debug!(
+                    "IP {} 丢包率 {:.0}% 超过上限 {:.0}%, 跳过",
+                    ip, stats.loss, max_loss
+                );
+                return None;
+            }
+            Some((ip, stats))
+        }
+        Err(results) => {
+            let elapsed = results
+                .iter()
+                .map(|result| result.elapsed())
+                .max()
+                .unwrap_or_default();
+            debug!(
+                "IP {} 不可达 (最长耗时 {}ms): {}",
+                ip,
+                elapsed.as_millis(),
+                summarize_failures(&results).join(", ")
+            );
+            None
+        }
+    }
+}
+
+// 按延迟统计比较两个候选 IP, 统计相同时按 IP 地址排序, 保证顺序确定
+fn candidate_cmp(a: &(IpAddr, PingStats), b: &(IpAddr, PingStats)) -> Ordering {
+    a.1.rank_cmp(&b.1).then(a.0.cmp(&b.0))
+}
+
+// 按 `candidate_cmp` 排序的候选 IP, 用于整体排序时保留最好的一部分
+struct RankedCandidate((IpAddr, PingStats));
+
+impl PartialEq for RankedCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedCandidate {}
+
+impl PartialOrd for RankedCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RankedCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        candidate_cmp(&self.0, &other.0)
+    }
+}
+
+// 整体排序时保留的延迟统计最好的候选 IP, 内存占用不超过数量上限
+struct BestCandidates {
+    // 大顶堆的堆顶为最差的候选, 超出数量上限时将其移除
+    heap: BinaryHeap<RankedCandidate>,
+    limit: usize,
+    // 加入过的候选总数
+    count: usize,
+}
+
+impl BestCandidates {
+    fn new(limit: usize) -> BestCandidates {
+        BestCandidates {
+            heap: BinaryHeap::new(),
+            limit,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, candidate: (IpAddr, PingStats)) {
+        self.heap.push(RankedCandidate(candidate));
+        if self.heap.len() > self.limit {
+            self.heap.pop();
+        }
+        self.count += 1;
+    }
+
+    // 按 `candidate_cmp` 从好到差返回保留的候选
+    fn into_sorted_vec(self) -> Vec<(IpAddr, PingStats)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ranked| ranked.0)
+            .collect()
+    }
+}
+
+/// 检测本机按绑定设置是否具备 IPv6 连通性。
+///
+/// 先确认系统存在 IPv6 路由, 再尝试与 Cloudflare 的 IPv6 地址建立 TCP 连接。
+pub async fn check_ipv6_connectivity(binding: &SocketBinding) -> bool {
+    let probe_addr = SocketAddr::new(IpAddr::V6(IPV6_PROBE_ADDRESS), 80);
+
+    // 检查是否存在 IPv6 路由, UDP connect 不会发送任何数据包
+    let route_ok = match binding.udp_socket(&probe_addr) {
+        Ok(socket) => socket.connect(probe_addr).await.is_ok(),
+        Err(_) => false,
+    };
+    if !route_ok {
+        debug!("本机不存在 IPv6 路由");
+        return false;
+    }
+
+    match timeout(Duration::from_secs(3), binding.connect(probe_addr)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            debug!("无法连接 IPv6 地址 {}: {}", probe_addr, e);
+            false
+        }
+        Err(_) => {
+            debug!("连接 IPv6 地址 {} 超时", probe_addr);
+            false
+        }
+    }
+}
+
+/// 将主端下发的 IP 段展开为惰性生成的 IP 列表。
+///
+/// 展开前会应用允许 / 排除列表, 若本机不具备 IPv6 连通性, 则跳过其中的 IPv6 段。
+pub async fn ip_cidr_to_ips(
+    ip_cidr: Vec<String>,
+    catalog: &IpCatalog,
+    ip_filter: &IpFilter,
+    sample_config: &SampleConfig,
+    ipv6_available: bool,
+) -> Result<Box<dyn Iterator<Item = IpAddr> + Send>, Box<dyn Error>> {
+    // 展开目录关键字并应用 IPv4 / IPv6 过滤
+    let networks: Vec<IpNetwork> = catalog.resolve(&ip_cidr)?;
+
+    // 应用允许 / 排除列表
+    let before_filter = available_addresses(&networks);
+    let mut networks: Vec<IpNetwork> = ip_filter.apply(networks);
+    let excluded = before_filter.saturating_sub(available_addresses(&networks));
+    if excluded > 0 {
+        info!("根据允许 / 排除列表排除了 {} 个地址", excluded);
+    }
+
+    if !ipv6_available {
+        let ipv6_networks: Vec<IpNetwork> = networks
+            .iter()
+            .filter(|net| net.is_ipv6())
+            .cloned()
+            .collect();
+        if !ipv6_networks.is_empty() {
+            warn!(
+                "本机不具备 IPv6 连通性, 跳过 {} 个 IPv6 段 ({} 个地址)",
+                ipv6_networks.len(),
+                available_addresses(&ipv6_networks)
+            );
+            networks.retain(|net| net.is_ipv4());
+        }
+    }
+
+    info!(
+        "共 {} 个可用地址, 采样 {} 个地址",
+        available_addresses(&networks),
+        sampled_addresses(&networks, sample_config)
+    );
+
+    // 按采样设置惰性抽取地址
+    Ok(sample_networks(networks, sample_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 构造一次探测记录, latency 为 None 表示超时
+    fn probe(latency: Option<u64>) -> ProbeResult {
+        let outcome = match latency {
+            Some(ms) => ProbeOutcome::Ok(Duration::from_millis(ms)),
+            None => ProbeOutcome::Timeout,
+        };
+        ProbeResult::new(
+            "104.16.0.1:443".parse().unwrap(),
+            SystemTime::now(),
+            outcome,
+        )
+    }
+
+    fn stats(latencies: &[Option<u64>]) -> PingStats {
+        let results: Vec<ProbeResult> = latencies.iter().map(|latency| probe(*latency)).collect();
+        PingStats::from_results(&results).unwrap()
+    }
+
+    #[test]
+    fn percentile_nearest_rank() {
+        let sorted: Vec<u128> = (1..=20).collect();
+        assert_eq!(percentile(&sorted, 0), 1);
+        assert_eq!(percentile(&sorted, 50), 10);
+        assert_eq!(percentile(&sorted, 95), 19);
+        assert_eq!(percentile(&sorted, 100), 20);
+        assert_eq!(percentile(&[7], 95), 7);
+    }
+
+    #[test]
+    fn stats_from_results() {
+        let stats = stats(&[Some(30), None, Some(10), Some(20)]);
+        assert_eq!(stats.min, 10);
+        assert_eq!(stats.median, 20);
+        assert_eq!(stats.p95, 30);
+        assert_eq!(stats.jitter, 15.0);
+        assert_eq!(stats.loss, 25.0);
+        assert_eq!(stats.port, 443);
+        assert_eq!(stats.results.len(), 4);
+    }
+
+    #[test]
+    fn stats_none_when_all_lost() {
+        assert!(PingStats::from_results(&[probe(None), probe(None)]).is_none());
+    }
+
+    #[test]
+    fn qualify_rejects_slow_and_lossy() {
+        let ip: IpAddr = "104.16.0.1".parse().unwrap();
+        let qualified = |latencies: &[Option<u64>], max_loss| {
+            qualify(ip, Ok(stats(latencies)), 20, max_loss).is_some()
+        };
+        assert!(qualified(&[Some(10), Some(20), Some(30)], 0.0));
+        assert!(!qualified(&[Some(10), Some(30), Some(30)], 0.0));
+        assert!(!qualified(&[Some(10), None, Some(10)], 30.0));
+        assert!(qualified(&[Some(10), None, Some(10)], 50.0));
+        assert!(qualify(ip, Err(vec![probe(None)]), 20, 100.0).is_none());
+    }
+
+    #[test]
+    fn best_candidates_keeps_the_best() {
+        let mut best = BestCandidates::new(3);
+        for (last_octet, median) in [(1, 40), (2, 10), (3, 50), (4, 20), (5, 10), (6, 30)] {
+            let ip = IpAddr::from([104, 16, 0, last_octet]);
+            best.push((ip, stats(&[Some(median)])));
+        }
+        assert_eq!(best.count, 6);
+        let kept: Vec<(IpAddr, u128)> = best
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(ip, stats)| (ip, stats.median))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                (IpAddr::from([104, 16, 0, 2]), 10),
+                (IpAddr::from([104, 16, 0, 5]), 10),
+                (IpAddr::from([104, 16, 0, 4]), 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn candidate_order() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let mut candidates = [
+            (ip("104.16.0.5"), stats(&[Some(20), Some(20), Some(20)])),
+            (ip("104.16.0.4"), stats(&[Some(10), Some(12), Some(10)])),
+            (ip("104.16.0.3"), stats(&[Some(10), None, Some(10)])),
+            (ip("104.16.0.2"), stats(&[Some(10), Some(10), Some(10)])),
+            (ip("104.16.0.1"), stats(&[Some(10), Some(10), Some(10)])),
+        ];
+        candidates.sort_by(candidate_cmp);
+        let order: Vec<IpAddr> = candidates.iter().map(|(ip, _)| *ip).collect();
+        assert_eq!(
+            order,
+            [
+                ip("104.16.0.1"),
+                ip("104.16.0.2"),
+                ip("104.16.0.3"),
+                ip("104.16.0.4"),
+                ip("104.16.0.5"),
+            ]
+        );
+    }
 }
@@ -0,0 +1,114 @@
+use crate::trace::TlsTiming;
+
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// 单次探测的结果。
+#[derive(Debug, Clone)]
+pub enum ProbeOutcome {
+    /// 探测成功, 附带延迟
+    Ok(Duration),
+    /// 超时未应答
+    Timeout,
+    /// 连接被拒绝
+    Refused,
+    /// 连接被重置或中止
+    Reset,
+    /// 其他 IO 错误
+    Error(Arc<io::Error>),
+}
+
+impl ProbeOutcome {
+    /// 根据 IO 操作的结果对探测结果分类。
+    pub fn from_io(result: io::Result<Duration>) -> ProbeOutcome {
+        match result {
+            Ok(duration) => ProbeOutcome::Ok(duration),
+            Err(e) => match e.kind() {
+                io::ErrorKind::TimedOut => ProbeOutcome::Timeout,
+                io::ErrorKind::ConnectionRefused => ProbeOutcome::Refused,
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
+                    ProbeOutcome::Reset
+                }
+                _ => ProbeOutcome::Error(Arc::new(e)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ProbeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeOutcome::Ok(duration) => write!(f, "成功 ({}ms)", duration.as_millis()),
+            ProbeOutcome::Timeout => write!(f, "超时"),
+            ProbeOutcome::Refused => write!(f, "连接被拒绝"),
+            ProbeOutcome::Reset => write!(f, "连接被重置"),
+            ProbeOutcome::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// 对单个地址的一次探测记录。
+#[derive(Debug, Clone)]
+pub struct ProbeResult {
+    /// 探测的目标地址, ICMP 探测时端口为 0
+    pub target: SocketAddr,
+    /// 探测结果
+    pub outcome: ProbeOutcome,
+    /// 探测开始时间
+    pub started_at: SystemTime,
+    /// 探测结束时间
+    pub finished_at: SystemTime,
+    /// TLS 探测各阶段耗时, 仅 TLS 探测成功时存在
+    pub tls: Option<TlsTiming>,
+}
+
+impl ProbeResult {
+    /// 以当前时间作为结束时间构建探测记录。
+    pub fn new(target: SocketAddr, started_at: SystemTime, outcome: ProbeOutcome) -> ProbeResult {
+        ProbeResult {
+            target,
+            outcome,
+            started_at,
+            finished_at: SystemTime::now(),
+            tls: None,
+        }
+    }
+
+    /// 返回探测成功时的延迟。
+    pub fn latency(&self) -> Option<Duration> {
+        match self.outcome {
+            ProbeOutcome::Ok(duration) => Some(duration),
+            _ => None,
+        }
+    }
+
+    /// 返回探测从开始到结束的耗时, 失败的探测同样有耗时。
+    pub fn elapsed(&self) -> Duration {
+        self.finished_at
+            .duration_since(self.started_at)
+            .unwrap_or_default()
+    }
+}
+
+/// 汇总一组探测中失败的记录, 相同地址的相同原因合并计数, 如 `1.1.1.1:443 超时 ×3`。
+pub fn summarize_failures(results: &[ProbeResult]) -> Vec<String> {
+    let mut failures: Vec<(String, usize)> = Vec::new();
+    for result in results.iter().filter(|result| result.latency().is_none()) {
+        let reason = format!("{} {}", result.target, result.outcome);
+        match failures
+            .iter_mut()
+            .find(|(existing, _)| *existing == reason)
+        {
+            Some((_, count)) => *count += 1,
+            None => failures.push((reason, 1)),
+        }
+    }
+    failures
+        .into_iter()
+        .map(|(reason, count)| format!("{} ×{}", reason, count))
+        .collect()
+}
@@ -0,0 +1,134 @@
+use crate::bind::SocketBinding;
+
+use log::debug;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, OnceLock},
+};
+use tokio::{net::UdpSocket, time::Instant};
+
+// 注册表以出口绑定与是否为 IPv4 区分套接字
+type RegistryKey = (SocketBinding, bool);
+
+/// 按出口绑定与地址族共享的套接字注册表, 每个绑定与地址族的套接字在首次使用时创建。
+///
+/// 原始套接字会收到本机所有对应协议的报文, 每次探测各开一个套接字在并发时开销为 O(n²)。
+pub struct Registry<T> {
+    entries: OnceLock<Mutex<HashMap<RegistryKey, Arc<T>>>>,
+}
+
+impl<T> Registry<T> {
+    /// 创建空的注册表, 可用于初始化静态变量。
+    pub const fn new() -> Registry<T> {
+        Registry {
+            entries: OnceLock::new(),
+        }
+    }
+
+    /// 获取指定绑定与地址族的套接字, 尚未创建时调用 `open` 创建并保存。
+    pub fn get_or_open(
+        &self,
+        binding: &SocketBinding,
+        is_ipv4: bool,
+        open: impl FnOnce() -> io::Result<Arc<T>>,
+    ) -> io::Result<Arc<T>> {
+        let entries = self.entries.get_or_init(|| Mutex::new(HashMap::new()));
+        let mut entries = entries.lock().unwrap();
+        let key: RegistryKey = (binding.clone(), is_ipv4);
+        if let Some(entry) = entries.get(&key) {
+            return Ok(entry.clone());
+        }
+
+        let entry = open()?;
+        entries.insert(key, entry.clone());
+        Ok(entry)
+    }
+}
+
+/// 由后台任务接收报文的共享套接字。
+pub trait PacketHandler: Send + Sync + Sized + 'static {
+    /// 用于日志的名称
+    const NAME: &'static str;
+
+    /// 返回接收报文的套接字。
+    fn socket(&self) -> &UdpSocket;
+
+    /// 处理收到的报文, `received` 为收到报文的时间。
+    fn handle_packet(&self, packet: &[u8], from: IpAddr, received: Instant);
+
+    /// 启动后台任务持续接收报文并交给 `handle_packet` 处理, 任务在接收出错时结束。
+    fn spawn_receiver(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1500];
+            loop {
+                match self.socket().recv_from(&mut buffer).await {
+                    Ok((len, from)) => {
+                        let received = Instant::now();
+                        self.handle_packet(&buffer[..len], from.ip(), received);
+                    }
+                    Err(e) => {
+                        debug!("{} 接收失败: {}", Self::NAME, e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// 返回指定地址族的未指定地址, 用于对尚无目标的套接字应用绑定设置。
+pub fn unspecified_address(is_ipv4: bool) -> SocketAddr {
+    if is_ipv4 {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    }
+}
+
+/// 跳过 IPv4 原始套接字收到的数据中的 IP 头部, 数据不完整时返回 None。
+pub fn skip_ipv4_header(packet: &[u8]) -> Option<&[u8]> {
+    let ip_header_len = ((packet.first()? & 0x0f) as usize) * 4;
+    packet.get(ip_header_len..)
+}
+
+/// 计算 RFC 1071 定义的互联网校验和。
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_matches_rfc1071_example() {
+        // RFC 1071 第 3 节的示例, 反码和为 0xddf2
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(internet_checksum(&data), !0xddf2);
+        assert_eq!(internet_checksum(&[0xff]), !0xff00);
+    }
+
+    #[test]
+    fn skip_ipv4_header_by_ihl() {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x46;
+        packet[24] = 0xab;
+        assert_eq!(skip_ipv4_header(&packet), Some(&[0xab, 0, 0, 0][..]));
+        assert_eq!(skip_ipv4_header(&packet[..20]), None);
+        assert_eq!(skip_ipv4_header(&[]), None);
+    }
+}
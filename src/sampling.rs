@@ -0,0 +1,326 @@
+use crate::args::Args;
+
+use ipnetwork::IpNetwork;
+use log::info;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::net::IpAddr;
+
+// 采样时划分子网所用的前缀长度, IPv4 按 /24, IPv6 按 /120
+const IPV4_SUBNET_PREFIX: u8 = 24;
+const IPV6_SUBNET_PREFIX: u8 = 120;
+
+// 单个 IP 段最多访问的子网数量, 防止超大 IPv6 段无限展开
+const MAX_SUBNETS_PER_RANGE: u128 = 1 << 16;
+
+/// IP 段采样设置。
+#[derive(Debug, Clone)]
+pub struct SampleConfig {
+    /// 每个子网 (IPv4 /24, IPv6 /120) 随机抽取的地址数量, 0 表示不采样 (IPv6 段仍抽取 1 个)
+    pub per_subnet: u32,
+    /// 采样地址总数上限, 0 表示不限制
+    pub max_total: usize,
+    /// 随机种子, 设置后每次采样结果相同
+    pub seed: Option<u64>,
+}
+
+impl SampleConfig {
+    /// 根据命令行参数构建采样设置。
+    pub fn from_args(args: &Args) -> SampleConfig {
+        SampleConfig {
+            per_subnet: args.sample_per_subnet,
+            max_total: args.sample_max_total,
+            seed: args.sample_seed,
+        }
+    }
+}
+
+/// 计算一组 IP 段中可用地址的总数。
+pub fn available_addresses(networks: &[IpNetwork]) -> u128 {
+    networks.iter().fold(0u128, |total, network| {
+        let size = 1u128
+            .checked_shl((address_bits(network) - network.prefix()) as u32)
+            .unwrap_or(u128::MAX);
+        total.saturating_add(size)
+    })
+}
+
+/// 计算按采样设置将会从一组 IP 段中抽取的地址数量, 无需实际展开地址。
+pub fn sampled_addresses(networks: &[IpNetwork], config: &SampleConfig) -> u128 {
+    let total = networks.iter().fold(0u128, |total, network| {
+        let (subnet_limit, hosts_per_subnet) = sample_shape(network, config.per_subnet);
+        total.saturating_add(subnet_limit.saturating_mul(hosts_per_subnet))
+    });
+
+    if config.max_total > 0 {
+        total.min(config.max_total as u128)
+    } else {
+        total
+    }
+}
+
+/// 按采样设置从一组 IP 段中惰性抽取地址。
+///
+/// 每个 IP 段按 IPv4 /24 或 IPv6 /120 划分子网, 以随机顺序访问子网并从中随机抽取地址,
+/// 各 IP 段之间轮流取址, 因此设置总数上限时不会只取到排在前面的 IP 段。
+/// 地址在迭代时才生成, 内存占用与 IP 段大小无关。
+pub fn sample_networks(
+    networks: Vec<IpNetwork>,
+    config: &SampleConfig,
+) -> Box<dyn Iterator<Item = IpAddr> + Send> {
+    let mut rng: StdRng = match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let samplers: Vec<NetworkSampler> = networks
+        .into_iter()
+        .map(|network| {
+            // 超大的 IP 段 (主要是 IPv6 段) 只访问随机的一部分子网, 记录以免误以为已完整测试
+            let subnets = subnet_count(&network);
+            if subnets > MAX_SUBNETS_PER_RANGE {
+                info!(
+                    "IP 段 {} 共 {} 个子网, 超过上限 {}, 仅随机访问其中 {} 个",
+                    network, subnets, MAX_SUBNETS_PER_RANGE, MAX_SUBNETS_PER_RANGE
+                );
+            }
+            NetworkSampler::new(network, config.per_subnet, rng.gen())
+        })
+        .collect();
+
+    let interleaved = Interleave {
+        iters: samplers,
+        next: 0,
+    };
+
+    if config.max_total > 0 {
+        Box::new(interleaved.take(config.max_total))
+    } else {
+        Box::new(interleaved)
+    }
+}
+
+// 返回 IP 段采样时访问的子网数量及每个子网抽取的地址数量
+fn sample_shape(network: &IpNetwork, per_subnet: u32) -> (u128, u128) {
+    let host_count = 1u128 << (address_bits(network) - subnet_prefix(network));
+
+    let hosts_per_subnet = match per_subnet {
+        0 if network.is_ipv4() => host_count,
+        0 => 1,
+        n => (n as u128).min(host_count),
+    };
+
+    (
+        subnet_count(network).min(MAX_SUBNETS_PER_RANGE),
+        hosts_per_subnet,
+    )
+}
+
+// 返回 IP 段划分出的子网数量, 超出 u128 范围时为 u128::MAX
+fn subnet_count(network: &IpNetwork) -> u128 {
+    1u128
+        .checked_shl((subnet_prefix(network) - network.prefix()) as u32)
+        .unwrap_or(u128::MAX)
+}
+
+// 返回 IP 段划分子网所用的前缀长度
+fn subnet_prefix(network: &IpNetwork) -> u8 {
+    if network.is_ipv4() {
+        IPV4_SUBNET_PREFIX
+    } else {
+        IPV6_SUBNET_PREFIX
+    }
+    .max(network.prefix())
+}
+
+fn address_bits(network: &IpNetwork) -> u8 {
+    if network.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+// 对 [0, 2^bits) 的随机排列, 利用 (a * i + b) mod 2^bits 在 a 为奇数时为双射的性质,
+// 无需保存整个序列即可按随机顺序访问
+struct Permutation {
+    mask: u128,
+    a: u128,
+    b: u128,
+}
+
+impl Permutation {
+    fn new(bits: u8, rng: &mut StdRng) -> Permutation {
+        let mask = if bits >= 128 {
+            u128::MAX
+        } else {
+            (1u128 << bits) - 1
+        };
+        Permutation {
+            mask,
+            a: rng.gen::<u128>() | 1,
+            b: rng.gen::<u128>(),
+        }
+    }
+
+    fn get(&self, index: u128) -> u128 {
+        self.a.wrapping_mul(index).wrapping_add(self.b) & self.mask
+    }
+}
+
+// 单个 IP 段的采样迭代器
+struct NetworkSampler {
+    rng: StdRng,
+    base: u128,
+    is_ipv4: bool,
+    host_bits: u8,
+    subnets: Permutation,
+    subnet_limit: u128,
+    subnet_index: u128,
+    hosts_per_subnet: u128,
+    hosts: Option<(u128, Permutation)>,
+    host_index: u128,
+}
+
+impl NetworkSampler {
+    fn new(network: IpNetwork, per_subnet: u32, seed: u64) -> NetworkSampler {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let is_ipv4 = network.is_ipv4();
+        let host_bits = address_bits(&network) - subnet_prefix(&network);
+        let (subnet_limit, hosts_per_subnet) = sample_shape(&network, per_subnet);
+
+        let base = match network.network() {
+            IpAddr::V4(addr) => u32::from(addr) as u128,
+            IpAddr::V6(addr) => u128::from(addr),
+        };
+
+        let subnets = Permutation::new(subnet_prefix(&network) - network.prefix(), &mut rng);
+
+        NetworkSampler {
+            rng,
+            base,
+            is_ipv4,
+            host_bits,
+            subnets,
+            subnet_limit,
+            subnet_index: 0,
+            hosts_per_subnet,
+            hosts: None,
+            host_index: 0,
+        }
+    }
+}
+
+impl Iterator for NetworkSampler {
+    type Item = IpAddr;
+
+    fn next(&mut self) -> Option<IpAddr> {
+        loop {
+            if let Some((subnet_base, hosts)) = &self.hosts {
+                if self.host_index < self.hosts_per_subnet {
+                    let offset = hosts.get(self.host_index);
+                    self.host_index += 1;
+                    let addr = subnet_base + offset;
+                    return Some(if self.is_ipv4 {
+                        IpAddr::V4((addr as u32).into())
+                    } else {
+                        IpAddr::V6(addr.into())
+                    });
+                }
+            }
+
+            if self.subnet_index >= self.subnet_limit {
+                return None;
+            }
+
+            let subnet_base = self.base + (self.subnets.get(self.subnet_index) << self.host_bits);
+            self.subnet_index += 1;
+            self.hosts = Some((subnet_base, Permutation::new(self.host_bits, &mut self.rng)));
+            self.host_index = 0;
+        }
+    }
+}
+
+// 在多个迭代器之间轮流取值
+struct Interleave<I: Iterator> {
+    iters: Vec<I>,
+    next: usize,
+}
+
+impl<I: Iterator> Iterator for Interleave<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        while !self.iters.is_empty() {
+            if self.next >= self.iters.len() {
+                self.next = 0;
+            }
+            match self.iters[self.next].next() {
+                Some(item) => {
+                    self.next += 1;
+                    return Some(item);
+                }
+                None => {
+                    self.iters.remove(self.next);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn config(per_subnet: u32, max_total: usize) -> SampleConfig {
+        SampleConfig {
+            per_subnet,
+            max_total,
+            seed: Some(1),
+        }
+    }
+
+    #[test]
+    fn permutation_is_bijective() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for bits in [0, 1, 8, 12] {
+            let permutation = Permutation::new(bits, &mut rng);
+            let values: HashSet<u128> = (0..1u128 << bits).map(|i| permutation.get(i)).collect();
+            assert_eq!(values.len(), 1 << bits);
+            assert!(values.iter().all(|v| *v < 1 << bits));
+        }
+    }
+
+    #[test]
+    fn full_ipv4_range_yields_every_address_once() {
+        let network: IpNetwork = "104.16.0.0/22".parse().unwrap();
+        let ips: Vec<IpAddr> = sample_networks(vec![network], &config(0, 0)).collect();
+        let unique: HashSet<&IpAddr> = ips.iter().collect();
+        assert_eq!(ips.len(), 1024);
+        assert_eq!(unique.len(), 1024);
+        assert!(ips.iter().all(|ip| network.contains(*ip)));
+        assert_eq!(sampled_addresses(&[network], &config(0, 0)), 1024);
+    }
+
+    #[test]
+    fn sampling_per_subnet_and_total() {
+        let networks: Vec<IpNetwork> = vec![
+            "104.16.0.0/22".parse().unwrap(),
+            "2606:4700::/112".parse().unwrap(),
+        ];
+        let sampled = sampled_addresses(&networks, &config(2, 0));
+        assert_eq!(sampled, 4 * 2 + 256 * 2);
+        assert_eq!(
+            sample_networks(networks.clone(), &config(2, 0)).count() as u128,
+            sampled
+        );
+
+        // 总数上限下各 IP 段轮流取址, 同一种子结果相同
+        let first: Vec<IpAddr> = sample_networks(networks.clone(), &config(2, 6)).collect();
+        assert_eq!(first.len(), 6);
+        assert_eq!(first.iter().filter(|ip| ip.is_ipv4()).count(), 3);
+        let second: Vec<IpAddr> = sample_networks(networks, &config(2, 6)).collect();
+        assert_eq!(first, second);
+    }
+}
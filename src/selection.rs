@@ -0,0 +1,318 @@
+use crate::{
+    args::Args, cfst_rpc::SpeedtestResponse, hijack::InterceptionSign, ping::PingStats,
+    speed::SpeedResult,
+};
+
+use clap::ValueEnum;
+use log::warn;
+use std::{net::IpAddr, time::Duration};
+use tokio::time::Instant;
+
+/// 从测速过的候选 IP 中选出上报 IP 的策略。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SelectionStrategy {
+    /// 选择第一个达到最低速度要求的 IP
+    FirstFit,
+    /// 对最先交给测速的 K 个 IP 测速, 选择其中速度最快的;
+    /// 仅在候选整体排序 (`CandidateOrder::Full`) 时为延迟最低的 K 个
+    TopK,
+    /// 在限定时间内尽量多测速, 选择其中速度最快的
+    TimeBudget,
+    /// 与 top-k 同样对 K 个 IP 测速, 按速度、延迟与抖动的加权得分选择
+    Weighted,
+}
+
+impl SelectionStrategy {
+    /// 返回策略的名称, 与主端下发时使用的名称一致。
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SelectionStrategy::FirstFit => "first-fit",
+            SelectionStrategy::TopK => "top-k",
+            SelectionStrategy::TimeBudget => "time-budget",
+            SelectionStrategy::Weighted => "weighted",
+        }
+    }
+
+    /// 解析主端下发的策略名称。
+    pub fn parse(name: &str) -> Option<SelectionStrategy> {
+        match name.trim().to_lowercase().replace('_', "-").as_str() {
+            "first-fit" => Some(SelectionStrategy::FirstFit),
+            "top-k" => Some(SelectionStrategy::TopK),
+            "time-budget" => Some(SelectionStrategy::TimeBudget),
+            "weighted" => Some(SelectionStrategy::Weighted),
+            _ => None,
+        }
+    }
+}
+
+/// 测速完成的候选 IP。
+#[derive(Debug, Clone)]
+pub struct SpeedCandidate {
+    /// 候选 IP
+    pub ip: IpAddr,
+    /// 延迟统计
+    pub ping: PingStats,
+    /// 测得的稳态总速度 (Mbps)
+    pub speed: f64,
+    /// 完整的测速结果
+    pub result: SpeedResult,
+    /// 劫持检测发现的迹象
+    pub signs: Vec<InterceptionSign>,
+    /// 开始测速的时间
+    pub started_at: Instant,
+    /// 测速完成的时间
+    pub finished_at: Instant,
+}
+
+/// 上报 IP 的选择设置。
+#[derive(Debug, Clone)]
+pub struct SelectionConfig {
+    /// 选择策略
+    pub strategy: SelectionStrategy,
+    /// top-k 与 weighted 策略测速的候选数量
+    pub top_k: usize,
+    /// time-budget 策略的测速时长
+    pub time_budget: Duration,
+    /// 加权得分中延迟中位数的权重
+    pub latency_weight: f64,
+    /// 加权得分中抖动的权重
+    pub jitter_weight: f64,
+    /// 加权得分中速度的权重
+    pub speed_weight: f64,
+}
+
+impl SelectionConfig {
+    /// 根据命令行参数构建选择设置。
+    pub fn from_args(args: &Args) -> SelectionConfig {
+        SelectionConfig {
+            strategy: args.select_strategy,
+            top_k: args.select_top_k.max(1),
+            time_budget: Duration::from_secs(args.select_time_budget_secs),
+            latency_weight: args.select_latency_weight,
+            jitter_weight: args.select_jitter_weight,
+            speed_weight: args.select_speed_weight,
+        }
+    }
+
+    /// 根据主端下发的任务覆盖本地设置, 返回本次任务使用的设置。
+    pub fn for_task(&self, speedtest_response: &SpeedtestResponse) -> SelectionConfig {
+        let mut config = self.clone();
+        let Some(selection) = &speedtest_response.selection else {
+            return config;
+        };
+
+        if !selection.strategy.is_empty() {
+            match SelectionStrategy::parse(&selection.strategy) {
+                Some(strategy) => config.strategy = strategy,
+                None => warn!(
+                    "主端下发了未知的选择策略 {}, 使用本地设置 {}",
+                    selection.strategy,
+                    config.strategy.as_str()
+                ),
+            }
+        }
+        if selection.top_k > 0 {
+            config.top_k = selection.top_k as usize;
+        }
+        if selection.time_budget_secs > 0 {
+            config.time_budget = Duration::from_secs(selection.time_budget_secs as u64);
+        }
+        if selection.latency_weight != 0.0
+            || selection.jitter_weight != 0.0
+            || selection.speed_weight != 0.0
+        {
+            config.latency_weight = selection.latency_weight as f64;
+            config.jitter_weight = selection.jitter_weight as f64;
+            config.speed_weight = selection.speed_weight as f64;
+        }
+        config
+    }
+
+    /// 根据已测速的候选判断是否停止测速。
+    ///
+    /// time-budget 策略在时长用尽后总是停止; 其他策略在尚无候选达到最低速度要求时总是继续,
+    /// 即在候选不足时退化为 first-fit。
+    pub fn should_stop(
+        &self,
+        tested: &[SpeedCandidate],
+        minimum_mbps: i32,
+        elapsed: Duration,
+    ) -> bool {
+        if self.budget_exhausted(elapsed) {
+            return true;
+        }
+        if !tested.iter().any(|c| meets_minimum(c, minimum_mbps)) {
+            return false;
+        }
+        match self.strategy {
+            SelectionStrategy::FirstFit => true,
+            SelectionStrategy::TopK | SelectionStrategy::Weighted => tested.len() >= self.top_k,
+            SelectionStrategy::TimeBudget => elapsed >= self.time_budget,
+        }
+    }
+
+    /// 判断 time-budget 策略的测速时长是否已用尽, 用尽后不再开始新的测速。
+    pub fn budget_exhausted(&self, elapsed: Duration) -> bool {
+        self.strategy == SelectionStrategy::TimeBudget && elapsed >= self.time_budget
+    }
+
+    /// 从已测速的候选中选出达到最低速度要求的最佳 IP, 没有符合要求的候选时返回 None。
+    pub fn choose(&self, tested: Vec<SpeedCandidate>, minimum_mbps: i32) -> Option<SpeedCandidate> {
+        let mut qualified = tested
+            .into_iter()
+            .filter(|c| meets_minimum(c, minimum_mbps));
+        match self.strategy {
+            SelectionStrategy::FirstFit => qualified.next(),
+            SelectionStrategy::TopK | SelectionStrategy::TimeBudget => {
+                qualified.max_by(|a, b| a.speed.total_cmp(&b.speed))
+            }
+            SelectionStrategy::Weighted => {
+                let qualified: Vec<SpeedCandidate> = qualified.collect();
+                let best = qualified
+                    .iter()
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| {
+                        self.score(a, &qualified)
+                            .total_cmp(&self.score(b, &qualified))
+                    })
+                    .map(|(index, _)| index)?;
+                qualified.into_iter().nth(best)
+            }
+        }
+    }
+
+    /// 计算候选的加权得分, 速度越快、延迟与抖动越低得分越高。
+    ///
+    /// 速度、延迟中位数与抖动分别除以 `tested` 中的最大值, 归一化到 0 至 1 之间,
+    /// 因此权重只表示各项的相对重要程度, 与单位及链路快慢无关。
+    pub fn score(&self, candidate: &SpeedCandidate, tested: &[SpeedCandidate]) -> f64 {
+        let normalized = |metric: fn(&SpeedCandidate) -> f64| {
+            let max = tested.iter().map(metric).fold(0.0, f64::max);
+            if max > 0.0 {
+                metric(candidate) / max
+            } else {
+                0.0
+            }
+        };
+        self.speed_weight * normalized(|c| c.speed)
+            - self.latency_weight * normalized(|c| c.ping.median as f64)
+            - self.jitter_weight * normalized(|c| c.ping.jitter)
+    }
+}
+
+fn meets_minimum(candidate: &SpeedCandidate, minimum_mbps: i32) -> bool {
+    candidate.speed.round() as i32 >= minimum_mbps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(last_octet: u8, speed: f64, median: u128, jitter: f64) -> SpeedCandidate {
+        SpeedCandidate {
+            ip: IpAddr::from([104, 16, 0, last_octet]),
+            ping: PingStats {
+                min: median,
+                median,
+                p95: median,
+                jitter,
+                loss: 0.0,
+                tls: None,
+                port: 443,
+                ports: Vec::new(),
+                results: Vec::new(),
+            },
+            speed,
+            result: SpeedResult::default(),
+            signs: Vec::new(),
+            started_at: Instant::now(),
+            finished_at: Instant::now(),
+        }
+    }
+
+    fn config(strategy: SelectionStrategy) -> SelectionConfig {
+        SelectionConfig {
+            strategy,
+            top_k: 3,
+            time_budget: Duration::from_secs(60),
+            latency_weight: 1.0,
+            jitter_weight: 1.0,
+            speed_weight: 1.0,
+        }
+    }
+
+    #[test]
+    fn should_stop_waits_for_a_qualified_candidate() {
+        let slow = [candidate(1, 5.0, 10, 0.0), candidate(2, 8.0, 20, 0.0)];
+        let elapsed = Duration::from_secs(1);
+        for strategy in [
+            SelectionStrategy::FirstFit,
+            SelectionStrategy::TopK,
+            SelectionStrategy::Weighted,
+        ] {
+            assert!(!config(strategy).should_stop(&slow, 10, elapsed));
+            assert!(!config(strategy).should_stop(&slow, 10, Duration::from_secs(120)));
+        }
+
+        let tested = [candidate(1, 5.0, 10, 0.0), candidate(2, 20.0, 20, 0.0)];
+        assert!(config(SelectionStrategy::FirstFit).should_stop(&tested, 10, elapsed));
+        assert!(!config(SelectionStrategy::TopK).should_stop(&tested, 10, elapsed));
+
+        let tested = [
+            candidate(1, 5.0, 10, 0.0),
+            candidate(2, 20.0, 20, 0.0),
+            candidate(3, 8.0, 30, 0.0),
+        ];
+        assert!(config(SelectionStrategy::TopK).should_stop(&tested, 10, elapsed));
+        assert!(config(SelectionStrategy::Weighted).should_stop(&tested, 10, elapsed));
+    }
+
+    #[test]
+    fn time_budget_stops_when_exhausted() {
+        let config = config(SelectionStrategy::TimeBudget);
+        let tested = [candidate(1, 50.0, 10, 0.0)];
+        assert!(!config.should_stop(&tested, 10, Duration::from_secs(30)));
+        assert!(config.should_stop(&[], 10, Duration::from_secs(60)));
+        assert!(config.budget_exhausted(Duration::from_secs(60)));
+        assert!(!SelectionConfig {
+            strategy: SelectionStrategy::TopK,
+            ..config
+        }
+        .budget_exhausted(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn choose_by_strategy() {
+        let tested = vec![
+            candidate(1, 5.0, 10, 0.0),
+            candidate(2, 20.0, 20, 0.0),
+            candidate(3, 40.0, 30, 0.0),
+        ];
+        let chosen = |strategy| {
+            config(strategy)
+                .choose(tested.clone(), 10)
+                .map(|candidate| candidate.ip)
+        };
+        assert_eq!(chosen(SelectionStrategy::FirstFit), Some(tested[1].ip));
+        assert_eq!(chosen(SelectionStrategy::TopK), Some(tested[2].ip));
+        assert_eq!(chosen(SelectionStrategy::TimeBudget), Some(tested[2].ip));
+        assert!(config(SelectionStrategy::TopK)
+            .choose(tested.clone(), 50)
+            .is_none());
+    }
+
+    #[test]
+    fn weighted_score_is_normalized() {
+        let config = config(SelectionStrategy::Weighted);
+        for scale in [1.0, 100.0] {
+            let tested = vec![
+                candidate(1, 100.0 * scale, 50, 1.0),
+                candidate(2, 90.0 * scale, 10, 1.0),
+            ];
+            assert!((config.score(&tested[0], &tested) + 1.0).abs() < 1e-9);
+            assert!((config.score(&tested[1], &tested) + 0.3).abs() < 1e-9);
+            let chosen = config.choose(tested.clone(), 10).unwrap();
+            assert_eq!(chosen.ip, tested[1].ip);
+        }
+    }
+}
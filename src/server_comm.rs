@@ -1,11 +1,19 @@
-use std::{error::Error, process::exit, time::Duration};
+use std::{error::Error, io, process::exit, time::Duration};
 
 use crate::{
-    cfst_rpc::*, cloudflare_speedtest_client::CloudflareSpeedtestClient, ping::ip_cidr_to_ips,
+    bind::SocketBinding,
+    cfst_rpc::*,
+    cloudflare_speedtest_client::CloudflareSpeedtestClient,
+    ping::{PingStats, ProbeMode},
+    probe::summarize_failures,
 };
 
+use hyper_util::rt::TokioIo;
 use log::{debug, error, info, warn};
-use tonic::transport::{Channel, Endpoint};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::lookup_host;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::service_fn;
 use uuid::Uuid;
 
 /**
@@ -16,10 +24,12 @@ use uuid::Uuid;
  * 如果连接失败, 它将打印连接错误的消息, 并退出程序。
  *
  * @param server_url 服务器URL, 用于建立连接。
+ * @param binding 与主端通信使用的出站绑定设置, 未设置时由内核选择出口。
  * @return CloudflareSpeedtestClient实例, 用于后续速度测试操作。
  */
 pub async fn init_client(
     server_url: String,
+    binding: &SocketBinding,
 ) -> Result<CloudflareSpeedtestClient<Channel>, Box<dyn Error>> {
     // 尝试连接到指定的服务器
     let endpoint = match Endpoint::from_shared("http://".to_string() + &server_url) {
@@ -30,21 +40,32 @@ pub async fn init_client(
         }
     };
 
-    let client = match CloudflareSpeedtestClient::connect(
+    let endpoint = endpoint
+        .timeout(Duration::from_secs(5))
+        .connect_timeout(Duration::from_secs(5))
+        .tcp_keepalive(Some(Duration::from_secs(5)))
+        .http2_keep_alive_interval(Duration::from_secs(5))
+        .keep_alive_timeout(Duration::from_secs(5))
+        .keep_alive_while_idle(true);
+
+    // 设置了绑定时使用自定义连接器, 否则沿用默认连接方式
+    let channel = if binding.is_default() {
+        endpoint.connect().await
+    } else {
+        let binding = binding.clone();
         endpoint
-            .timeout(Duration::from_secs(5))
-            .connect_timeout(Duration::from_secs(5))
-            .tcp_keepalive(Some(Duration::from_secs(5)))
-            .http2_keep_alive_interval(Duration::from_secs(5))
-            .keep_alive_timeout(Duration::from_secs(5))
-            .keep_alive_while_idle(true),
-    )
-    .await
-    {
+            .connect_with_connector(service_fn(move |uri: Uri| {
+                let binding = binding.clone();
+                async move { connect_control(uri, &binding).await.map(TokioIo::new) }
+            }))
+            .await
+    };
+
+    let client = match channel {
         Ok(tmp) => {
             // 连接成功, 打印成功消息并返回客户端实例
             info!("成功连接服务器");
-            tmp
+            CloudflareSpeedtestClient::new(tmp)
         }
         Err(e) => {
             // 连接失败, 打印错误消息并返回错误
@@ -52,7 +73,33 @@ pub async fn init_client(
             return Err(Box::new(tonic::Status::aborted("无法连接服务器")));
         }
     };
-    return Ok(client);
+    Ok(client)
+}
+
+// 按绑定设置连接主端, 依次尝试解析得到的地址
+async fn connect_control(uri: Uri, binding: &SocketBinding) -> io::Result<tokio::net::TcpStream> {
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "服务器地址缺少主机名"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(80);
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "无法解析服务器地址");
+    for addr in lookup_host((host, port)).await? {
+        match binding.connect(addr).await {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                SockRef::from(&stream)
+                    .set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(5)))?;
+                return Ok(stream);
+            }
+            Err(e) => {
+                debug!("无法通过 {} 连接主端 {}: {}", binding, addr, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
 }
 
 /// 异步发送启动配置请求并处理响应。
@@ -101,7 +148,7 @@ pub async fn send_bootstrap(
     debug!("BootStrapResponse Message: {:?}", response);
 
     // 检查启动请求是否成功
-    if response.clone().success != true {
+    if !response.clone().success {
         error!(
             "Bootstrap 信息已成功, 但返回错误 (也许是 Bootstrap Token 设置错误): {:?}",
             response.clone()
@@ -113,19 +160,18 @@ pub async fn send_bootstrap(
     let session_token: String = response.clone().session_token;
 
     // 如果响应指示需要升级, 则发出警告
-    if response.clone().should_upgrade == true {
+    if response.clone().should_upgrade {
         warn!("该后端需更新, 建议更新至最新版本");
     }
 
     // 返回响应、节点ID和会话令牌
-    return Ok((response, node_id, session_token));
+    Ok((response, node_id, session_token))
 }
 
-/// 异步发送速度测试请求到主端, 并返回速度测试响应和IP范围列表。
+/// 异步发送速度测试请求到主端, 并返回速度测试响应。
 ///
 /// 此函数使用提供的Cloudflare Speedtest客户端、节点ID和会话令牌来发起速度测试请求。
-/// 它首先构建一个速度测试请求, 然后发送该请求并处理响应。如果请求成功, 它将解析响应中的IP范围,
-/// 并将这些信息以及原始响应一起返回。
+/// 它首先构建一个速度测试请求, 然后发送该请求并返回响应, 响应中的IP范围由调用方展开。
 ///
 /// 参数:
 /// - `client`: 用于与主端通信的客户端。
@@ -133,13 +179,13 @@ pub async fn send_bootstrap(
 /// - `session_token`: 用于验证会话的令牌。
 ///
 /// 返回值:
-/// - `Result<(SpeedtestResponse, Vec<String>), Box<dyn Error>>`: 包含速度测试响应和IP范围列表的结果。
-/// 如果发生错误, 返回一个包含错误详情的Box<dyn Error>。
+/// - `Result<SpeedtestResponse, Box<dyn Error>>`: 包含速度测试响应的结果。
+///   如果发生错误, 返回一个包含错误详情的Box<dyn Error>。
 pub async fn send_speedtest(
     client: CloudflareSpeedtestClient<Channel>,
     node_id: String,
     session_token: String,
-) -> Result<(SpeedtestResponse, Vec<String>), Box<dyn Error>> {
+) -> Result<SpeedtestResponse, Box<dyn Error>> {
     // 构建速度测试请求
     let reqwest: SpeedtestRequest = SpeedtestRequest {
         session_token,
@@ -158,65 +204,140 @@ pub async fn send_speedtest(
     // 该代码解决了无法检测是否与主端断开连接的问题
     // 让我们感谢 Moohr!
     // 尝试读取流中的消息并处理可能的错误
-    loop {
-        return match stream.message().await {
-            Ok(Some(response)) => {
-                // Process the valid response message here
-                debug!("SpeedtestResponse Message: {:?}", response);
+    match stream.message().await {
+        Ok(Some(response)) => {
+            // Process the valid response message here
+            debug!("SpeedtestResponse Message: {:?}", response);
 
-                // Convert IP ranges to specific IP addresses list
-                let ip_ranges_ips = ip_cidr_to_ips(response.clone().ip_ranges).await?;
+            Ok(response)
+        }
+        Ok(None) => {
+            // The stream was closed by the sender
+            error!(
+                "与主端的流传输被迫关闭, 这可能是因为后端网络波动或主端崩溃, 正在尝试重新连接..."
+            );
+            // Handle the closure of the stream, possibly attempt to reconnect or exit
+            Err(Box::new(tonic::Status::aborted(
+                "Stream was closed by the sender.",
+            )))
+        }
+        Err(e) => {
+            // An error occurred while fetching the next message
+            error!("无法接收主端发送的消息, 正在尝试重新连接: {}", e);
+            Err(Box::new(e))
+        }
+    }
+}
 
-                // Exit the loop after processing the message or continue as needed
-                Ok((response, ip_ranges_ips))
-            }
-            Ok(None) => {
-                // The stream was closed by the sender
-                error!("与主端的流传输被迫关闭, 这可能是因为后端网络波动或主端崩溃, 正在尝试重新连接...");
-                // Handle the closure of the stream, possibly attempt to reconnect or exit
-                Err(Box::new(tonic::Status::aborted(
-                    "Stream was closed by the sender.",
-                )))
-            }
-            Err(e) => {
-                // An error occurred while fetching the next message
-                error!("无法接收主端发送的消息, 正在尝试重新连接: {}", e);
-                Err(Box::new(e))
+/// 根据测速结果构建上报主端的IP结果对象。
+///
+/// 延迟统计来自任务所用的探测方式, `latency` 默认取其中位数; 没有结果时延迟均为 -1。
+/// 调用方可在此基础上补充 ICMP 延迟、劫持检测结果、出口名称等字段。
+pub fn build_ip_result(
+    ip: String,
+    ping_stats: Option<PingStats>,
+    speed: i32,
+    probe_mode: ProbeMode,
+) -> IpResult {
+    match ping_stats {
+        Some(stats) => {
+            let tls = stats.tls.clone();
+            IpResult {
+                ip_address: ip,
+                // TLS 探测时 latency 仍表示 TCP 连接耗时
+                latency: tls
+                    .as_ref()
+                    .map_or(stats.median as i32, |t| t.connect as i32),
+                speed,
+                latency_min: stats.min as i32,
+                latency_median: stats.median as i32,
+                latency_p95: stats.p95 as i32,
+                jitter: stats.jitter as f32,
+                loss: stats.loss as f32,
+                icmp_latency: -1,
+                probe_mode: probe_mode.as_str().to_string(),
+                tcp_connect_ms: tls.as_ref().map_or(-1, |t| t.connect as i32),
+                tls_handshake_ms: tls.as_ref().map_or(-1, |t| t.handshake as i32),
+                ttfb_ms: tls
+                    .as_ref()
+                    .and_then(|t| t.ttfb)
+                    .map_or(-1, |ttfb| ttfb as i32),
+                colo: tls.and_then(|t| t.colo).unwrap_or_default(),
+                port: stats.port as i32,
+                port_latencies: stats
+                    .ports
+                    .iter()
+                    .map(|port| PortLatency {
+                        port: port.port as i32,
+                        latency: port.latency.map_or(-1, |latency| latency as i32),
+                    })
+                    .collect(),
+                suspected_interception: false,
+                interception_reasons: Vec::new(),
+                probe_failures: summarize_failures(&stats.results),
+                egress: String::new(),
+                concurrent_speedtests: 0,
+                speed_contended: false,
+                stream_speeds: Vec::new(),
+                upload_speed: -1,
+                speed_mean: -1.0,
+                speed_peak: -1.0,
+                speed_intervals: Vec::new(),
             }
-        };
+        }
+        None => IpResult {
+            ip_address: ip,
+            latency: -1,
+            speed,
+            latency_min: -1,
+            latency_median: -1,
+            latency_p95: -1,
+            jitter: -1.0,
+            loss: -1.0,
+            icmp_latency: -1,
+            probe_mode: probe_mode.as_str().to_string(),
+            tcp_connect_ms: -1,
+            tls_handshake_ms: -1,
+            ttfb_ms: -1,
+            colo: String::new(),
+            port: 0,
+            port_latencies: Vec::new(),
+            suspected_interception: false,
+            interception_reasons: Vec::new(),
+            probe_failures: Vec::new(),
+            egress: String::new(),
+            concurrent_speedtests: 0,
+            speed_contended: false,
+            stream_speeds: Vec::new(),
+            upload_speed: -1,
+            speed_mean: -1.0,
+            speed_peak: -1.0,
+            speed_intervals: Vec::new(),
+        },
     }
 }
 
 /// 异步发送速度测试结果到主端。
 ///
-/// 此函数接收速度测试的IP地址、ping值和速度, 以及一个Cloudflare速度测试客户端,
+/// 此函数接收由 `build_ip_result` 构建的IP结果对象 (每个出口一个), 以及一个Cloudflare速度测试客户端,
 /// 用于向主端发送速度测试结果。它还接收一个节点ID和会话令牌, 这些可能是用于
-/// 鉴权或标识测试来源的。
+/// 鉴权或标识测试来源的。节点拒绝执行任务 (如流量配额已用尽) 时, IP结果为空,
+/// `refusal_reason` 说明拒绝的原因, 否则为空字符串。
 ///
 /// 返回结果为速度测试响应, 或者一个错误盒子。如果成功发送了测试结果, 它将返回测试结果的副本。
 pub async fn send_speedtest_result(
-    ip: String,
-    ping: i32,
-    speed: i32,
+    ipresults: Vec<IpResult>,
     mut client: CloudflareSpeedtestClient<Channel>,
     node_id: String,
     session_token: String,
+    refusal_reason: String,
 ) -> Result<SpeedtestResultResponse, Box<dyn Error>> {
-    // 构建IP结果对象, 包含IP地址、延迟和速度信息。
-    let ipresult = IpResult {
-        ip_address: ip,
-        latency: ping,
-        speed,
-    };
-
-    // 将IP结果对象封装成一个vector, 以满足请求格式的要求。
-    let ipresult_vec: Vec<IpResult> = vec![ipresult];
-
-    // 构建速度测试结果请求, 包含IP结果、会话令牌和节点ID。
+    // 构建速度测试结果请求, 包含IP结果、会话令牌、节点ID和拒绝原因。
     let reqwest = SpeedtestResultRequest {
-        ip_results: ipresult_vec,
+        ip_results: ipresults,
         session_token,
         node_id,
+        refusal_reason,
     };
 
     // 打印调试信息, 显示即将发送的速度测试结果请求。
@@ -1,150 +1,882 @@
-use log::{error, info};
-use std::net::ToSocketAddrs;
-use std::process::exit;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::Instant;
+use crate::{
+    args::Args,
+    bind::SocketBinding,
+    http::{read_head, BodyDecoder},
+    limiter::BandwidthLimiter,
+    ping::ProbeMode,
+    trace::tls_connector,
+    traffic::{record_payload_traffic, record_traffic},
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{debug, error, info};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::lookup_host;
+use tokio::sync::watch;
+use tokio::time::{interval, interval_at, timeout, timeout_at, Instant, MissedTickBehavior};
 use tokio_rustls::{rustls, TlsConnector};
-use url::Url;
+use url::{Position, Url};
+
+// 并发测速的总速度达到节点带宽的该比例时, 认为各测速可能相互影响
+const CONTENDED_RATIO: f64 = 0.9;
+
+// 自适应连接数时, 每隔该时间评估一次吞吐量并决定是否增加连接
+const RAMP_INTERVAL: Duration = Duration::from_secs(1);
+
+// 增加连接后吞吐量的增幅低于该比例时, 认为吞吐量已趋于平稳
+const PLATEAU_GAIN: f64 = 0.1;
+
+// 下载测速跟随重定向的次数上限
+const MAX_REDIRECTS: usize = 5;
+
+// 下载测速的吞吐量采样间隔
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+// 提前结束测速前, 预热后的速度需要持续达到最低要求的时间
+const PROOF_TIME: Duration = Duration::from_secs(2);
+
+// 上传时每次写入的数据块大小
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+// 上传结束后等待服务器响应的最长时间
+const UPLOAD_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 测速设置。
+#[derive(Debug, Clone)]
+pub struct SpeedConfig {
+    /// 同时测速的 IP 数量上限
+    pub concurrency: usize,
+    /// 节点的最大带宽 (Mbps), 并发测速共享该带宽
+    pub max_mbps: i32,
+    /// 每个 IP 同时建立的下载连接数, 自适应时为上限
+    pub streams: usize,
+    /// 是否从单个连接开始逐步增加连接, 直到吞吐量不再明显增长
+    pub adaptive_streams: bool,
+    /// 下载开始后不计入稳态速度的预热时间, 排除 TCP 慢启动的影响
+    pub warmup: Duration,
+    /// 所有测速共享的带宽限制器, 未开启限速时为 None
+    pub bandwidth: Option<Arc<BandwidthLimiter>>,
+    /// 确认达到任务的最低速度要求后是否提前结束下载测速
+    pub early_stop: bool,
+    /// 上传测速发送的数据量 (字节)
+    pub upload_bytes: u64,
+    /// 上传测速的最长时间
+    pub upload_time: Duration,
+}
+
+impl SpeedConfig {
+    /// 根据命令行参数构建测速设置。
+    pub fn from_args(args: &Args) -> SpeedConfig {
+        SpeedConfig {
+            concurrency: args.speed_concurrency.max(1),
+            max_mbps: args.max_mbps,
+            streams: args.speed_streams.max(1),
+            adaptive_streams: args.speed_streams_adaptive,
+            warmup: Duration::from_millis(args.speed_warmup_ms),
+            bandwidth: BandwidthLimiter::from_args(args).map(Arc::new),
+            early_stop: args.speed_early_stop,
+            upload_bytes: args.upload_size_mb * 1_000_000,
+            upload_time: Duration::from_secs(args.upload_time_secs),
+        }
+    }
+
+    /// 返回本次任务实际的测速并发数。
+    ///
+    /// 节点带宽按并发数均分, 每个测速分得的带宽不低于任务的最低带宽要求,
+    /// 以免并发本身使达标的 IP 测不出应有的速度。
+    pub fn concurrency_for(&self, minimum_mbps: i32) -> usize {
+        if minimum_mbps <= 0 || self.max_mbps <= 0 {
+            return self.concurrency;
+        }
+        let fits = (self.max_mbps / minimum_mbps).max(1) as usize;
+        self.concurrency.min(fits)
+    }
+
+    /// 返回并发数为 `concurrency` 时每个测速分得的带宽 (Mbps)。
+    pub fn share_mbps(&self, concurrency: usize) -> f64 {
+        self.max_mbps as f64 / concurrency.max(1) as f64
+    }
+
+    /// 重叠测速的总速度是否接近节点带宽, 即测得的速度可能受到并发测速的压制。
+    pub fn is_contended(&self, overlapping: usize, total_mbps: f64) -> bool {
+        overlapping > 1 && total_mbps >= self.max_mbps as f64 * CONTENDED_RATIO
+    }
+}
+
+/// 单个 IP 的测速结果。
+#[derive(Debug, Clone, Default)]
+pub struct SpeedResult {
+    /// 排除预热时间后的稳态总速度 (Mbps), 测速时间不足预热时间时为平均速度, 测速失败时为 -1
+    pub mbps: f64,
+    /// 整个下载过程的平均总速度 (Mbps)
+    pub mean_mbps: f64,
+    /// 采样区间中最高的总速度 (Mbps)
+    pub peak_mbps: f64,
+    /// 每个采样区间的总速度 (Mbps), 从开始下载算起
+    pub interval_mbps: Vec<f64>,
+    /// 各连接的平均速度 (Mbps)
+    pub stream_mbps: Vec<f64>,
+}
+
+impl SpeedResult {
+    fn failed() -> SpeedResult {
+        SpeedResult {
+            mbps: -1.0,
+            mean_mbps: -1.0,
+            peak_mbps: -1.0,
+            interval_mbps: Vec::new(),
+            stream_mbps: Vec::new(),
+        }
+    }
+}
+
+// 测速连接, 按 URL 的协议为 TCP 或 TLS 连接
+trait SpeedStream: AsyncRead + AsyncWrite + Unpin + Send {
+    // 返回内核发送缓冲区中尚未被对端确认的字节数, 当前系统不支持时返回 None
+    fn unsent_bytes(&self) -> Option<u64>;
+}
+
+#[cfg(target_os = "linux")]
+impl<T: AsyncRead + AsyncWrite + AsRawFd + Unpin + Send> SpeedStream for T {
+    fn unsent_bytes(&self) -> Option<u64> {
+        let mut unsent: libc::c_int = 0;
+        // TIOCOUTQ 与 SIOCOUTQ 相同, 对 TCP 套接字返回发送队列中尚未被确认的字节数
+        let result = unsafe { libc::ioctl(self.as_raw_fd(), libc::TIOCOUTQ, &mut unsent) };
+        (result == 0).then_some(unsent.max(0) as u64)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> SpeedStream for T {
+    fn unsent_bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+// 测速 URL 与 IP 确定的连接目标, 同一 IP 的各连接共用
+struct SpeedTarget {
+    url: Url,
+    // 固定连接的 IP, 重定向到其他主机后为 None, 通过 DNS 解析
+    ip: Option<IpAddr>,
+    port: u16,
+    // HTTPS 使用的 SNI, HTTP 时为 None
+    server_name: Option<rustls::pki_types::ServerName<'static>>,
+    host: String,
+    path: String,
+    connector: TlsConnector,
+}
+
+impl SpeedTarget {
+    // 解析测速 URL, 以 URL 的主机名作为 SNI 与 Host 连接指定 IP
+    fn parse(speedtest_url: &str, ip: IpAddr, port: Option<u16>) -> Option<SpeedTarget> {
+        let url = match Url::parse(speedtest_url) {
+            Ok(parsed_url) => parsed_url,
+            Err(e) => {
+                error!("无法正确解析 Speedtest URL: {}", e);
+                return None;
+            }
+        };
+
+        // 探测得到的端口均为 HTTPS 端口, 仅用于 HTTPS URL
+        let port = match url.scheme() {
+            "https" => url.port().or(port),
+            _ => url.port(),
+        };
+
+        match SpeedTarget::new(url, Some(ip), port, tls_connector()) {
+            Ok(target) => Some(target),
+            Err(e) => {
+                error!("无法使用 Speedtest URL: {}", e);
+                None
+            }
+        }
+    }
+
+    fn new(
+        url: Url,
+        ip: Option<IpAddr>,
+        port: Option<u16>,
+        connector: TlsConnector,
+    ) -> Result<SpeedTarget, String> {
+        let tls = match url.scheme() {
+            "https" => true,
+            "http" => false,
+            scheme => return Err(format!("不支持的协议 {}", scheme)),
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("URL {} 中缺少主机名", url))?
+            .to_string();
+        let server_name = if tls {
+            // IPv6 地址形式的主机名需要去掉方括号
+            let name = host.trim_start_matches('[').trim_end_matches(']');
+            Some(
+                rustls::pki_types::ServerName::try_from(name.to_string())
+                    .map_err(|e| format!("无法获取 URL 中的域名 {}: {}", host, e))?,
+            )
+        } else {
+            None
+        };
+        let port = port
+            .or(url.port_or_known_default())
+            .ok_or_else(|| format!("URL {} 中缺少端口", url))?;
+
+        Ok(SpeedTarget {
+            // 保留 URL 中的查询参数
+            path: url[Position::BeforePath..Position::AfterQuery].to_string(),
+            url,
+            ip,
+            port,
+            server_name,
+            host,
+            connector,
+        })
+    }
+
+    // 按响应的 Location 构建重定向目标, 主机名不变时仍连接测试的 IP
+    fn redirect(&self, location: &str) -> Result<SpeedTarget, String> {
+        let url = self
+            .url
+            .join(location)
+            .map_err(|e| format!("无效的重定向地址 {}: {}", location, e))?;
+        let same_host = url.host_str() == self.url.host_str();
+        let ip = if same_host { self.ip } else { None };
+        // 协议与主机名均未改变且未指定端口时, 沿用原来的端口
+        let port = if same_host && url.scheme() == self.url.scheme() {
+            url.port().or(Some(self.port))
+        } else {
+            url.port()
+        };
+        SpeedTarget::new(url, ip, port, self.connector.clone())
+    }
+
+    // 按绑定设置建立连接, HTTPS 时完成 TLS 握手
+    async fn connect(&self, binding: &SocketBinding) -> Option<Box<dyn SpeedStream>> {
+        let addr = match self.ip {
+            // 直接使用 IP 与端口构建 SocketAddr, IPv6 地址无需额外处理
+            Some(ip) => SocketAddr::new(ip, self.port),
+            None => {
+                let name = self.host.trim_start_matches('[').trim_end_matches(']');
+                match lookup_host((name, self.port)).await {
+                    Ok(mut addrs) => match addrs.next() {
+                        Some(addr) => addr,
+                        None => {
+                            error!("无法解析 {}", self.host);
+                            return None;
+                        }
+                    },
+                    Err(e) => {
+                        error!("无法解析 {}: {}", self.host, e);
+                        return None;
+                    }
+                }
+            }
+        };
+
+        let stream = match binding.connect(addr).await {
+            Ok(tmp) => tmp,
+            Err(e) => {
+                error!("无法创立 Tcp 连接: {}", e);
+                return None;
+            }
+        };
+        // 连接的建立、关闭与 TLS 握手不经过 HTTP 计量, 按探测的估算计入流量
+        let setup = if self.server_name.is_some() {
+            ProbeMode::Tls
+        } else {
+            ProbeMode::Tcp
+        };
+        record_traffic(setup.probe_bytes(addr.ip(), false));
+
+        let Some(server_name) = &self.server_name else {
+            return Some(Box::new(stream));
+        };
+        match self.connector.connect(server_name.clone(), stream).await {
+            Ok(tmp) => Some(Box::new(tmp)),
+            Err(e) => {
+                error!("无法创立 Tls 连接: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Display for SpeedTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{} (IP {} 端口 {})", self.url, ip, self.port),
+            None => write!(f, "{} (DNS 解析)", self.url),
+        }
+    }
+}
+
+// 同一 IP 的各下载连接共享的进度
+struct DownloadProgress {
+    // 所有连接在同一时刻结束, 后加入的连接下载时间更短
+    deadline: Instant,
+    // 所有连接累计收到的响应体字节数
+    received: AtomicU64,
+    // 第一个连接收到响应头部的时间
+    first_body: OnceLock<Instant>,
+    // 提前结束测速的信号
+    stop: watch::Sender<bool>,
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+}
+
+// 判断预热后的速度是否已持续达到最低要求
+struct MinimumProof {
+    minimum_mbps: f64,
+    warmup: Duration,
+    baseline: Option<(Instant, u64)>,
+}
+
+impl MinimumProof {
+    fn is_proven(&mut self, first_body: Option<&Instant>, at: Instant, received: u64) -> bool {
+        let Some(first_body) = first_body else {
+            return false;
+        };
+        if at < *first_body + self.warmup {
+            return false;
+        }
+        let (since, base) = *self.baseline.get_or_insert((at, received));
+        let elapsed = at - since;
+        elapsed >= PROOF_TIME && to_mbps(received - base, elapsed) >= self.minimum_mbps
+    }
+}
+
+// 单个下载连接的统计
+struct StreamStats {
+    bytes: u64,
+    started_at: Instant,
+    finished_at: Instant,
+}
 
 /**
  * 测量给定IP地址和URL的下载速度。
  *
+ * 按测速设置对同一 IP 建立一个或多个下载连接, 汇总各连接的吞吐量。
+ * 支持 HTTP 与 HTTPS, 并跟随有限次数的重定向, 主机名不变时仍连接测试的 IP。
+ *
  * @param speedtest_url 测速URL, 用于发起下载请求。
  * @param ip 要测试速度的IP地址。
+ * @param port HTTPS 测速端口, URL 未指定端口时使用; 均未指定时 https 为 443, http 为 80。
  * @param speed_time 测速时间（秒）, 用于限制下载时间。
- * @return 返回下载速度（Mbps）。
+ * @param minimum_mbps 任务的最低速度要求, 开启提前结束时确认达到后即停止下载。
+ * @param binding 测速连接使用的出站绑定设置。
+ * @param config 测速设置, 决定下载连接数与限速。
+ * @return 返回稳态、平均与峰值下载速度, 以及各采样区间与各连接的下载速度（Mbps）。
  */
-pub async fn speed_one_ip(speedtest_url: String, ip: String, speed_time: u32) -> f64 {
-    let url = match Url::parse(speedtest_url.as_str()) {
-        Ok(parsed_url) => parsed_url,
-        Err(e) => {
-            error!("无法正确解析 Speedtest URL: {}", e);
-            return -1.0;
-        }
+pub async fn speed_one_ip(
+    speedtest_url: String,
+    ip: IpAddr,
+    port: Option<u16>,
+    speed_time: u32,
+    minimum_mbps: i32,
+    binding: &SocketBinding,
+    config: &SpeedConfig,
+) -> SpeedResult {
+    let Some(target) = SpeedTarget::parse(&speedtest_url, ip, port) else {
+        return SpeedResult::failed();
     };
 
-    let domain = match &url.domain() {
-        Some(tmp) => match rustls::pki_types::ServerName::try_from(tmp.to_string()) {
-            Ok(tmp) => tmp,
-            Err(e) => {
-                error!("无法获取 Speedtest URL 中的域名: {}", e);
-                return -1.0;
-            }
-        },
-        None => {
-            error!("无法获取 Speedtest URL 中的域名");
-            return -1.0;
-        }
+    let deadline = Instant::now() + Duration::from_secs(speed_time as u64);
+    let progress = DownloadProgress {
+        deadline,
+        received: AtomicU64::new(0),
+        first_body: OnceLock::new(),
+        stop: watch::Sender::new(false),
+        bandwidth: config.bandwidth.clone(),
+    };
+    let mut proof = config.early_stop.then_some(MinimumProof {
+        minimum_mbps: minimum_mbps as f64,
+        warmup: config.warmup,
+        baseline: None,
+    });
+    let mut running = FuturesUnordered::new();
+    let initial = if config.adaptive_streams {
+        1
+    } else {
+        config.streams
     };
+    for _ in 0..initial {
+        running.push(download_stream(&target, binding, &progress));
+    }
+    let mut opened = initial;
 
-    let port = url.port().unwrap_or(443);
+    let mut ramping = config.adaptive_streams && config.streams > 1;
+    let mut ticker = interval_at(Instant::now() + RAMP_INTERVAL, RAMP_INTERVAL);
+    let mut last_received: u64 = 0;
+    let mut best_rate: f64 = 0.0;
+    let mut streams: Vec<StreamStats> = Vec::new();
 
-    let addr = match (ip.as_str(), port).to_socket_addrs() {
-        Ok(mut iter) => match iter.next() {
-            Some(addr) => addr,
-            None => {
-                error!("无法正确解析 Speedtest URL");
-                return -1.0;
+    // 定期记录所有连接累计收到的响应体字节数
+    let mut sampler = interval(SAMPLE_INTERVAL);
+    sampler.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut samples: Vec<(Instant, u64)> = Vec::new();
+
+    loop {
+        tokio::select! {
+            Some(stats) = running.next() => {
+                if let Some(stats) = stats {
+                    streams.push(stats);
+                }
             }
-        },
-        Err(e) => {
-            error!("无法正确解析 Speedtest URL: {}", e);
-            return -1.0;
+            sampled_at = sampler.tick(), if !running.is_empty() => {
+                let total = progress.received.load(Ordering::Relaxed);
+                samples.push((sampled_at, total));
+                if proof
+                    .as_mut()
+                    .is_some_and(|proof| proof.is_proven(progress.first_body.get(), sampled_at, total))
+                {
+                    info!("IP {} 已确认达到最低速度要求 {}Mbps, 提前结束测速", ip, minimum_mbps);
+                    progress.stop.send_replace(true);
+                    proof = None;
+                    ramping = false;
+                }
+            }
+            _ = ticker.tick(), if ramping => {
+                let total = progress.received.load(Ordering::Relaxed);
+                let rate = (total - last_received) as f64;
+                last_received = total;
+                if rate > best_rate * (1.0 + PLATEAU_GAIN)
+                    && opened < config.streams
+                    && Instant::now() + RAMP_INTERVAL < deadline
+                {
+                    best_rate = rate;
+                    running.push(download_stream(&target, binding, &progress));
+                    opened += 1;
+                } else {
+                    ramping = false;
+                }
+            }
+            else => break,
         }
-    };
+    }
 
-    let path = url.path();
+    if streams.is_empty() {
+        return SpeedResult::failed();
+    }
 
-    let request = format!(
-        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
-        path,
-        domain.to_str()
-    );
+    // 平均速度以最早开始下载到最晚结束下载的时间计算
+    let started_at = streams.iter().map(|s| s.started_at).min().unwrap();
+    let finished_at = streams.iter().map(|s| s.finished_at).max().unwrap();
+    let bytes_downloaded: u64 = streams.iter().map(|s| s.bytes).sum();
+    let mean_mbps = to_mbps(bytes_downloaded, finished_at - started_at);
+    let stream_mbps: Vec<f64> = streams
+        .iter()
+        .map(|s| to_mbps(s.bytes, s.finished_at - s.started_at))
+        .collect();
 
-    let mut root_cert_store = rustls::RootCertStore::empty();
-    root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    samples.push((finished_at, bytes_downloaded));
+    let intervals = Intervals::from_samples(&samples, started_at, started_at + config.warmup);
+    let download_speed_mbps = intervals.steady_mbps.unwrap_or(mean_mbps);
 
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
+    // 记录测速结果。
+    if stream_mbps.len() > 1 {
+        info!(
+            "IP: {}, 速度: {}mbps, 平均: {}mbps, 峰值: {}mbps, 连接数: {}, 各连接速度: {:?}",
+            ip,
+            download_speed_mbps,
+            mean_mbps,
+            intervals.peak_mbps,
+            stream_mbps.len(),
+            stream_mbps
+        );
+    } else {
+        info!(
+            "IP: {}, 速度: {}mbps, 平均: {}mbps, 峰值: {}mbps",
+            ip, download_speed_mbps, mean_mbps, intervals.peak_mbps
+        );
+    }
+    debug!("IP {} 各采样区间速度: {:?}", ip, intervals.mbps);
 
-    let connector = TlsConnector::from(Arc::new(config));
+    SpeedResult {
+        mbps: download_speed_mbps,
+        mean_mbps,
+        peak_mbps: intervals.peak_mbps,
+        interval_mbps: intervals.mbps,
+        stream_mbps,
+    }
+}
 
-    let stream = match TcpStream::connect(&addr).await {
-        Ok(tmp) => tmp,
-        Err(e) => {
-            error!("无法创立 Tcp 连接: {}", e);
-            return -1.0;
+// 采样区间的速度统计
+struct Intervals {
+    mbps: Vec<f64>,
+    peak_mbps: f64,
+    steady_mbps: Option<f64>,
+}
+
+impl Intervals {
+    // 由累计字节数采样计算开始下载后各区间的速度, 开始于预热结束前的区间不计入稳态速度
+    fn from_samples(
+        samples: &[(Instant, u64)],
+        started_at: Instant,
+        warmed_at: Instant,
+    ) -> Intervals {
+        let mut mbps: Vec<f64> = Vec::new();
+        let mut steady_bytes: u64 = 0;
+        let mut steady_time = Duration::ZERO;
+        for pair in samples.windows(2) {
+            let ((from, from_bytes), (to, to_bytes)) = (pair[0], pair[1]);
+            if from < started_at || to <= from {
+                continue;
+            }
+            let bytes = to_bytes.saturating_sub(from_bytes);
+            mbps.push(to_mbps(bytes, to - from));
+            if from >= warmed_at {
+                steady_bytes += bytes;
+                steady_time += to - from;
+            }
         }
-    };
+        Intervals {
+            peak_mbps: mbps.iter().copied().fold(0.0, f64::max),
+            steady_mbps: (!steady_time.is_zero()).then(|| to_mbps(steady_bytes, steady_time)),
+            mbps,
+        }
+    }
+}
 
-    let mut stream = match connector.connect(domain, stream).await {
-        Ok(tmp) => tmp,
-        Err(e) => {
-            error!("无法创立 Tls 连接: {}", e);
-            return -1.0;
+// 建立一个下载连接并读取到截止时间, 无法建立连接时返回 None
+async fn download_stream(
+    target: &SpeedTarget,
+    binding: &SocketBinding,
+    progress: &DownloadProgress,
+) -> Option<StreamStats> {
+    let deadline = progress.deadline;
+    let mut stop = progress.stop.subscribe();
+    let ip = target.ip.map_or_else(String::new, |ip| ip.to_string());
+
+    // 跟随有限次数的重定向, 记录重定向链以便排查
+    let mut chain: Vec<String> = vec![target.to_string()];
+    let mut redirected: Option<SpeedTarget> = None;
+    let (mut stream, head, rest) = loop {
+        let current = redirected.as_ref().unwrap_or(target);
+        let mut stream = current.connect(binding).await?;
+
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            current.path, current.host
+        );
+        if let Err(e) = stream.write_all(request.as_bytes()).await {
+            error!("无法写入请求: {}", e);
+            return None;
+        }
+        record_payload_traffic(request.len() as u64);
+
+        // 非 2xx 响应 (如边缘返回的 403 / 404 错误页) 不计入测速
+        let (head, rest) = match timeout_at(deadline, read_head(&mut stream)).await {
+            Ok(Ok((head, rest))) => {
+                record_payload_traffic(head.wire_len() + rest.len() as u64);
+                (head, rest)
+            }
+            Ok(Err(e)) => {
+                error!("IP {} 无法读取测速响应: {}", ip, e);
+                return None;
+            }
+            Err(_) => {
+                error!("IP {} 等待测速响应超时", ip);
+                return None;
+            }
+        };
+        if !head.is_redirect() {
+            break (stream, head, rest);
+        }
+
+        let _ = stream.shutdown().await;
+        if chain.len() > MAX_REDIRECTS {
+            error!("IP {} 测速请求重定向次数过多: {}", ip, chain.join(" -> "));
+            return None;
+        }
+        let Some(location) = head.header("Location") else {
+            error!("IP {} 测速请求返回 {} 但缺少 Location", ip, head.status);
+            return None;
+        };
+        match current.redirect(location) {
+            Ok(next) => {
+                chain.push(format!("{} {}", head.status, next));
+                redirected = Some(next);
+            }
+            Err(e) => {
+                error!("IP {} 无法跟随测速请求的重定向: {}", ip, e);
+                return None;
+            }
         }
     };
+    if chain.len() > 1 {
+        debug!("IP {} 测速请求重定向: {}", ip, chain.join(" -> "));
+    }
 
-    match stream.write_all(request.as_bytes()).await {
-        Ok(_) => {}
+    if !head.is_success() {
+        error!("IP {} 测速请求返回 {} {}", ip, head.status, head.reason);
+        return None;
+    }
+    let mut body = match head.framing() {
+        Ok(framing) => BodyDecoder::new(framing),
         Err(e) => {
-            error!("无法写入请求: {}", e)
+            error!("IP {} 测速响应无效: {}", ip, e);
+            return None;
         }
-    }
+    };
 
-    let start_time = Instant::now();
+    // 从收到响应头部开始计时, 只统计响应体的字节数
+    let started_at = Instant::now();
+    progress.first_body.get_or_init(|| started_at);
 
     let mut buffer = [0; 1024];
 
-    let mut data = 0;
+    let mut data: u64 = 0;
 
+    let mut pending = rest;
     loop {
-        match stream.read(&mut buffer).await {
+        if !pending.is_empty() {
+            match body.decode(&pending) {
+                Ok(n) => {
+                    data += n;
+                    progress.received.fetch_add(n, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    error!("下载文件出现错误: {}", e);
+                    break;
+                }
+            }
+            pending.clear();
+        }
+        // 响应体读取完整或到达测速时间, 退出循环。
+        // 数据持续可读时读取不会超时, 需要主动检查测速时间
+        if body.is_done() || Instant::now() >= deadline {
+            break;
+        }
+        let read = tokio::select! {
+            read = timeout_at(deadline, stream.read(&mut buffer)) => read,
+            // 已确认达到最低速度要求
+            _ = stop.wait_for(|stop| *stop) => break,
+        };
+        match read {
             // 读取结束, 退出循环。
             // 没有则退出
-            Ok(0) => break,
-            // 成功读取数据, 累加到总下载大小。
-            // 有则把接收到的放到计数器里
-            Ok(n) => {
-                data += n;
-                if start_time.elapsed().as_secs_f64() >= speed_time as f64 {
-                    break;
+            Ok(Ok(0)) => {
+                if let Err(e) = body.finish() {
+                    error!("下载文件出现错误: {}", e);
                 }
+                break;
             }
-            Err(e) => {
-                eprintln!("下载文件出现错误: {}", e);
-                exit(1);
+            // 成功读取数据, 交给解码器统计其中的响应体; 开启限速时按读取的字节数等待
+            Ok(Ok(n)) => {
+                record_payload_traffic(n as u64);
+                pending.extend_from_slice(&buffer[..n]);
+                if let Some(bandwidth) = &progress.bandwidth {
+                    bandwidth.consume(n).await;
+                }
+            }
+            Ok(Err(e)) => {
+                error!("下载文件出现错误: {}", e);
+                break;
             }
+            // 到达测速时间
+            Err(_) => break,
         }
     }
 
-    stream.shutdown().await.unwrap();
+    let finished_at = Instant::now();
+    let _ = stream.shutdown().await;
     drop(stream);
 
-    // 计算下载速度（Mbps）。
-    // 已下载
-    let bytes_downloaded = data;
+    Some(StreamStats {
+        bytes: data,
+        started_at,
+        finished_at,
+    })
+}
+
+/**
+ * 测量给定IP地址和URL的上传速度。
+ *
+ * 向上传 URL POST 随机生成的数据, 发送完设置的数据量或到达设置的时长后结束,
+ * TLS 与 SNI 的处理与下载测速相同。写入失败, 或数据全部发出后未能收到 2xx 响应时视为失败。
+ * 到达时长结束时, Linux 上不计入仍停留在内核发送缓冲区中的数据。
+ *
+ * @param upload_url 上传测速URL, 由主端随任务下发。
+ * @param ip 要测试速度的IP地址。
+ * @param port HTTPS 测速端口, URL 未指定端口时使用; 均未指定时 https 为 443, http 为 80。
+ * @param binding 测速连接使用的出站绑定设置。
+ * @param config 测速设置, 决定上传的数据量与时长。
+ * @return 返回上传速度（Mbps）, 失败时为 -1。
+ */
+pub async fn upload_one_ip(
+    upload_url: String,
+    ip: IpAddr,
+    port: Option<u16>,
+    binding: &SocketBinding,
+    config: &SpeedConfig,
+) -> f64 {
+    let Some(target) = SpeedTarget::parse(&upload_url, ip, port) else {
+        return -1.0;
+    };
+    let Some(mut stream) = target.connect(binding).await else {
+        return -1.0;
+    };
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        target.path,
+        target.host,
+        config.upload_bytes
+    );
+    if let Err(e) = stream.write_all(request.as_bytes()).await {
+        error!("无法写入请求: {}", e);
+        return -1.0;
+    }
+    record_payload_traffic(request.len() as u64);
 
-    // 总用时
-    let time_taken: f64 = start_time.elapsed().as_secs_f64();
+    // 使用随机内容, 避免链路上的压缩影响结果
+    let mut chunk = vec![0u8; UPLOAD_CHUNK];
+    StdRng::from_entropy().fill(&mut chunk[..]);
 
+    let start_time = Instant::now();
+    let deadline = start_time + config.upload_time;
+    let mut sent: u64 = 0;
+    let mut write_failed = false;
+
+    while sent < config.upload_bytes {
+        let n = (config.upload_bytes - sent).min(chunk.len() as u64) as usize;
+        match timeout_at(deadline, stream.write_all(&chunk[..n])).await {
+            Ok(Ok(())) => {
+                sent += n as u64;
+                record_payload_traffic(n as u64);
+            }
+            Ok(Err(e)) => {
+                error!("上传数据出现错误: {}", e);
+                write_failed = true;
+                break;
+            }
+            // 到达测速时间
+            Err(_) => break,
+        }
+    }
+
+    // 写入失败多是服务器提前拒绝了请求并断开连接, 尽量读取响应以记录原因, 不计算速度
+    if write_failed {
+        if let Ok(Ok((head, _))) = timeout(UPLOAD_RESPONSE_TIMEOUT, read_head(&mut stream)).await {
+            record_payload_traffic(head.wire_len());
+            error!("IP {} 上传请求返回 {} {}", ip, head.status, head.reason);
+        }
+        return -1.0;
+    }
+
+    // 数据全部发出后等待服务器响应, 确认数据已送达而非仍停留在本地发送缓冲区
+    if sent == config.upload_bytes {
+        let response = timeout(UPLOAD_RESPONSE_TIMEOUT, async {
+            stream.flush().await?;
+            read_head(&mut stream).await
+        })
+        .await;
+        match response {
+            Ok(Ok((head, _))) => {
+                record_payload_traffic(head.wire_len());
+                if !head.is_success() {
+                    error!("IP {} 上传请求返回 {} {}", ip, head.status, head.reason);
+                    return -1.0;
+                }
+            }
+            Ok(Err(e)) => {
+                error!("IP {} 无法读取上传请求的响应: {}", ip, e);
+                return -1.0;
+            }
+            Err(_) => {
+                error!("IP {} 等待上传请求的响应超时", ip);
+                return -1.0;
+            }
+        }
+    }
+
+    let time_taken = start_time.elapsed();
+    // 到达测速时间时, 写入成功的数据可能仍停留在内核发送缓冲区, 只计入已被对端确认的部分;
+    // TLS 连接缓冲区中的为加密后的记录, 扣除量会略大于对应的明文
+    let delivered = if sent < config.upload_bytes {
+        sent.saturating_sub(stream.unsent_bytes().unwrap_or(0))
+    } else {
+        sent
+    };
+    let _ = stream.shutdown().await;
+    drop(stream);
+
+    if delivered == 0 {
+        return -1.0;
+    }
+    let upload_speed_mbps = to_mbps(delivered, time_taken);
+
+    // 记录测速结果。
+    info!("IP: {}, 上传速度: {}mbps", ip, upload_speed_mbps);
+
+    upload_speed_mbps
+}
+
+// 根据下载字节数与用时计算速度（Mbps）
+fn to_mbps(bytes: u64, time_taken: Duration) -> f64 {
     // 下载总 Bits
-    let bits_downloaded: f64 = bytes_downloaded as f64 * 8.0;
+    let bits_downloaded: f64 = bytes as f64 * 8.0;
 
     // bps 计算
-    let download_speed_bps: f64 = bits_downloaded / time_taken;
+    let download_speed_bps: f64 = bits_downloaded / time_taken.as_secs_f64();
 
     // bps -> kbps
     let download_speed_kbps: f64 = download_speed_bps / 1000.0;
 
     // kbps -> mbps
-    let download_speed_mbps: f64 = download_speed_kbps / 1000.0;
+    download_speed_kbps / 1000.0
+}
 
-    // 记录测速结果。
-    info!("IP: {}, 速度: {}mbps", addr.ip(), download_speed_mbps);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    download_speed_mbps
+    #[test]
+    fn intervals_from_samples() {
+        let started_at = Instant::now();
+        let at = |ms: u64| started_at + Duration::from_millis(ms);
+        let samples = [
+            (started_at - Duration::from_millis(250), 0),
+            (at(0), 0),
+            (at(250), 250_000),
+            (at(500), 750_000),
+            (at(750), 1_000_000),
+        ];
+        let intervals = Intervals::from_samples(&samples, started_at, at(250));
+        assert_eq!(intervals.mbps, [8.0, 16.0, 8.0]);
+        assert_eq!(intervals.peak_mbps, 16.0);
+        assert_eq!(intervals.steady_mbps, Some(12.0));
+
+        let intervals = Intervals::from_samples(&samples, started_at, at(750));
+        assert_eq!(intervals.steady_mbps, None);
+    }
+
+    #[test]
+    fn minimum_proof_after_warmup() {
+        let first_body = Instant::now();
+        let at = |secs: u64| first_body + Duration::from_secs(secs);
+        let proof = || MinimumProof {
+            minimum_mbps: 8.0,
+            warmup: Duration::from_secs(1),
+            baseline: None,
+        };
+
+        let mut fast = proof();
+        assert!(!fast.is_proven(None, at(5), 10_000_000));
+        assert!(!fast.is_proven(Some(&first_body), at(0), 0));
+        assert!(!fast.is_proven(Some(&first_body), at(1), 1_000_000));
+        assert!(!fast.is_proven(Some(&first_body), at(2), 2_000_000));
+        assert!(fast.is_proven(Some(&first_body), at(3), 3_000_000));
+
+        let mut slow = proof();
+        assert!(!slow.is_proven(Some(&first_body), at(1), 1_000_000));
+        assert!(!slow.is_proven(Some(&first_body), at(3), 2_000_000));
+    }
 }
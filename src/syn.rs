@@ -0,0 +1,385 @@
+use crate::{
+    bind::SocketBinding,
+    probe::{ProbeOutcome, ProbeResult},
+    raw::{internet_checksum, skip_ipv4_header, unspecified_address, PacketHandler, Registry},
+};
+
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    time::{timeout, Instant},
+};
+
+// TCP 标志位
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+// SYN 报文长度: 20 字节 TCP 头部 + 4 字节 MSS 选项
+const SYN_PACKET_LEN: usize = 24;
+const RST_PACKET_LEN: usize = 20;
+
+// SYN 报文中通告的 MSS 与窗口大小, 与常见系统的默认值一致
+const SYN_MSS: u16 = 1460;
+const SYN_WINDOW: u16 = 64240;
+
+// 扫描器使用的源端口范围, 位于 Linux 默认的临时端口范围 (32768-60999) 之外,
+// 避免与本机连接使用的源端口冲突
+const SOURCE_PORT_MIN: u16 = 61000;
+const SOURCE_PORT_MAX: u16 = 65535;
+
+// 每个出口绑定与地址族对应一个扫描器, 首次使用时创建
+static SCANNERS: Registry<SynScanner> = Registry::new();
+
+/// 对单个 IP 的指定端口发送一次 SYN, 以收到 SYN-ACK 的耗时作为延迟, 返回探测记录。
+///
+/// 使用原始套接字自行构造报文, 不占用本地连接, 收到 SYN-ACK 后立即回复 RST;
+/// 收到 RST 视为连接被拒绝。需要 root 或 CAP_NET_RAW, 调用前应通过 `syn_available` 检查。
+pub async fn syn_ping(
+    ip: IpAddr,
+    port: u16,
+    timeout_ms: i32,
+    binding: &SocketBinding,
+) -> ProbeResult {
+    let target = SocketAddr::new(ip, port);
+    let time_out = Duration::from_millis(timeout_ms as u64);
+    let started_at = SystemTime::now();
+    let result = match scanner(binding, ip.is_ipv4()) {
+        Ok(scanner) => scanner.probe(target, time_out, binding).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &result {
+        if e.kind() != io::ErrorKind::TimedOut && e.kind() != io::ErrorKind::ConnectionRefused {
+            debug!("IP {} SYN 探测失败: {}", ip, e);
+        }
+    }
+    ProbeResult::new(target, started_at, ProbeOutcome::from_io(result))
+}
+
+/// 检测本机能否按出站绑定对指定地址族创建发送 TCP 报文的原始套接字。
+///
+/// 检查时即创建该绑定与地址族的扫描器, 后续探测直接复用。
+pub fn syn_available(binding: &SocketBinding, is_ipv4: bool) -> bool {
+    match scanner(binding, is_ipv4) {
+        Ok(_) => true,
+        Err(e) => {
+            debug!(
+                "无法创建 {} SYN 扫描器: {}",
+                if is_ipv4 { "IPv4" } else { "IPv6" },
+                e
+            );
+            false
+        }
+    }
+}
+
+// 获取或创建指定绑定与地址族的扫描器
+fn scanner(binding: &SocketBinding, is_ipv4: bool) -> io::Result<Arc<SynScanner>> {
+    SCANNERS.get_or_open(binding, is_ipv4, || SynScanner::open(binding, is_ipv4))
+}
+
+// 对端对 SYN 的应答
+enum SynReply {
+    SynAck(Instant),
+    Reset,
+}
+
+// 等待应答的探测, 以对端地址与 SYN 的初始序号区分
+type PendingKey = (SocketAddr, u32);
+
+// 同一出口绑定与地址族共享的 SYN 扫描器, 一个原始套接字负责发送, 后台任务负责接收应答
+struct SynScanner {
+    socket: UdpSocket,
+    is_ipv4: bool,
+    source_port: u16,
+    pending: Mutex<HashMap<PendingKey, oneshot::Sender<SynReply>>>,
+}
+
+impl SynScanner {
+    fn open(binding: &SocketBinding, is_ipv4: bool) -> io::Result<Arc<SynScanner>> {
+        let domain = if is_ipv4 { Domain::IPV4 } else { Domain::IPV6 };
+        let socket = Socket::new(domain, Type::RAW, Some(Protocol::TCP))?;
+
+        // 原始套接字会收到本机所有的 TCP 报文, 由内核只放行发往扫描器源端口的报文
+        let source_port =
+            SOURCE_PORT_MIN + rand::random::<u16>() % (SOURCE_PORT_MAX - SOURCE_PORT_MIN);
+        if let Err(e) = attach_port_filter(&socket, is_ipv4, source_port) {
+            debug!("无法为 SYN 扫描器设置报文过滤: {}", e);
+        }
+
+        // 只应用网卡与防火墙标记, 源地址由报文校验和计算时确定
+        let unbound = SocketBinding {
+            address_v4: None,
+            address_v6: None,
+            ..binding.clone()
+        };
+        unbound.apply(&socket, &unspecified_address(is_ipv4))?;
+
+        socket.set_nonblocking(true)?;
+        let socket: std::net::UdpSocket = socket.into();
+
+        let scanner = Arc::new(SynScanner {
+            socket: UdpSocket::from_std(socket)?,
+            is_ipv4,
+            source_port,
+            pending: Mutex::new(HashMap::new()),
+        });
+
+        // 扫描器创建后常驻
+        scanner.clone().spawn_receiver();
+        Ok(scanner)
+    }
+
+    async fn probe(
+        &self,
+        target: SocketAddr,
+        time_out: Duration,
+        binding: &SocketBinding,
+    ) -> io::Result<Duration> {
+        let source = source_address(binding, &target)?;
+        let sequence: u32 = rand::random();
+        let packet = build_segment(source, target, self.source_port, sequence, TCP_SYN);
+
+        let (sender, receiver) = oneshot::channel();
+        let key: PendingKey = (target, sequence);
+        self.pending.lock().unwrap().insert(key, sender);
+
+        let start = Instant::now();
+        if let Err(e) = self
+            .socket
+            .send_to(&packet, SocketAddr::new(target.ip(), 0))
+            .await
+        {
+            self.pending.lock().unwrap().remove(&key);
+            return Err(e);
+        }
+
+        let reply = timeout(time_out, receiver).await;
+        self.pending.lock().unwrap().remove(&key);
+        match reply {
+            Ok(Ok(SynReply::SynAck(received))) => {
+                // 自行复位连接, 不依赖内核对未知连接的 RST
+                let reset = build_segment(
+                    source,
+                    target,
+                    self.source_port,
+                    sequence.wrapping_add(1),
+                    TCP_RST,
+                );
+                let _ = self
+                    .socket
+                    .send_to(&reset, SocketAddr::new(target.ip(), 0))
+                    .await;
+                Ok(received.duration_since(start))
+            }
+            Ok(Ok(SynReply::Reset)) => Err(io::ErrorKind::ConnectionRefused.into()),
+            Ok(Err(_)) | Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+impl PacketHandler for SynScanner {
+    const NAME: &'static str = "SYN 扫描器";
+
+    fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    // 解析收到的 TCP 报文, 唤醒匹配的探测
+    fn handle_packet(&self, packet: &[u8], from: IpAddr, received: Instant) {
+        // IPv4 原始套接字收到的数据包含 IP 头部, 需要跳过
+        let segment = if self.is_ipv4 {
+            skip_ipv4_header(packet)
+        } else {
+            Some(packet)
+        };
+        let Some(segment) = segment.filter(|segment| segment.len() >= RST_PACKET_LEN) else {
+            return;
+        };
+
+        let source_port = u16::from_be_bytes([segment[0], segment[1]]);
+        let destination_port = u16::from_be_bytes([segment[2], segment[3]]);
+        if destination_port != self.source_port {
+            return;
+        }
+        let ack = u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]);
+        let flags = segment[13];
+
+        let reply = if flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK {
+            SynReply::SynAck(received)
+        } else if flags & TCP_RST != 0 && flags & TCP_FIN == 0 {
+            SynReply::Reset
+        } else {
+            return;
+        };
+
+        let key: PendingKey = (SocketAddr::new(from, source_port), ack.wrapping_sub(1));
+        if let Some(sender) = self.pending.lock().unwrap().remove(&key) {
+            let _ = sender.send(reply);
+        }
+    }
+}
+
+// 为原始套接字设置只放行目标端口为 `port` 的 TCP 报文的过滤器 (classic BPF)
+#[cfg(target_os = "linux")]
+fn attach_port_filter(socket: &Socket, is_ipv4: bool, port: u16) -> io::Result<()> {
+    use libc::{
+        sock_filter, BPF_ABS, BPF_B, BPF_H, BPF_IND, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_LDX,
+        BPF_MSH, BPF_RET,
+    };
+
+    let instruction = |code: u32, k: u32, jt: u8, jf: u8| sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    // IPv4 原始套接字的数据从 IP 头部开始, 先按头部长度跳过; IPv6 的数据从 TCP 头部开始
+    let mut program = if is_ipv4 {
+        vec![
+            // X = 4 * (IP 头部第一个字节 & 0x0f), 即 IP 头部长度
+            instruction(BPF_LDX | BPF_B | BPF_MSH, 0, 0, 0),
+            // A = TCP 头部偏移 2 处的目标端口
+            instruction(BPF_LD | BPF_H | BPF_IND, 2, 0, 0),
+        ]
+    } else {
+        vec![instruction(BPF_LD | BPF_H | BPF_ABS, 2, 0, 0)]
+    };
+    program.extend([
+        // 目标端口相同时放行整个报文, 否则丢弃
+        instruction(BPF_JMP | BPF_JEQ | BPF_K, port as u32, 0, 1),
+        instruction(BPF_RET | BPF_K, u32::MAX, 0, 0),
+        instruction(BPF_RET | BPF_K, 0, 0, 0),
+    ]);
+    socket.attach_filter(&program)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn attach_port_filter(_socket: &Socket, _is_ipv4: bool, _port: u16) -> io::Result<()> {
+    Ok(())
+}
+
+// 查询内核连接目标地址时将使用的源地址, UDP connect 不会发送任何数据包
+fn source_address(binding: &SocketBinding, target: &SocketAddr) -> io::Result<IpAddr> {
+    if let Some(address) = binding.local_address(target) {
+        return Ok(address);
+    }
+    let socket = binding.udp_socket(target)?;
+    let socket: std::net::UdpSocket = socket.into_std()?;
+    socket.connect(target)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+// 构造不带 IP 头部的 TCP 报文, SYN 报文附带 MSS 选项
+fn build_segment(
+    source: IpAddr,
+    target: SocketAddr,
+    source_port: u16,
+    sequence: u32,
+    flags: u8,
+) -> Vec<u8> {
+    let len = if flags & TCP_SYN != 0 {
+        SYN_PACKET_LEN
+    } else {
+        RST_PACKET_LEN
+    };
+    let mut segment = vec![0u8; len];
+    segment[0..2].copy_from_slice(&source_port.to_be_bytes());
+    segment[2..4].copy_from_slice(&target.port().to_be_bytes());
+    segment[4..8].copy_from_slice(&sequence.to_be_bytes());
+    segment[12] = ((len / 4) as u8) << 4;
+    segment[13] = flags;
+    if flags & TCP_SYN != 0 {
+        segment[14..16].copy_from_slice(&SYN_WINDOW.to_be_bytes());
+        segment[20] = 2;
+        segment[21] = 4;
+        segment[22..24].copy_from_slice(&SYN_MSS.to_be_bytes());
+    }
+
+    // 校验和包含源地址、目标地址组成的伪头部
+    let mut pseudo: Vec<u8> = Vec::with_capacity(40 + len);
+    match (source, target.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            pseudo.extend_from_slice(&source.octets());
+            pseudo.extend_from_slice(&destination.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        (source, destination) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            pseudo.extend_from_slice(&to_v6(source).octets());
+            pseudo.extend_from_slice(&to_v6(destination).octets());
+            pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+        }
+    }
+    pseudo.extend_from_slice(&segment);
+    let checksum = internet_checksum(&pseudo);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 按伪头部重新计算报文的校验和, 报文正确时结果为 0
+    fn verify(source: IpAddr, target: SocketAddr, segment: &[u8]) -> u16 {
+        let mut pseudo: Vec<u8> = Vec::new();
+        match (source, target.ip()) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                pseudo.extend_from_slice(&source.octets());
+                pseudo.extend_from_slice(&destination.octets());
+                pseudo.extend_from_slice(&[0, 6, 0, segment.len() as u8]);
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                pseudo.extend_from_slice(&source.octets());
+                pseudo.extend_from_slice(&destination.octets());
+                pseudo.extend_from_slice(&[0, 0, 0, segment.len() as u8, 0, 0, 0, 6]);
+            }
+            _ => unreachable!(),
+        }
+        pseudo.extend_from_slice(segment);
+        internet_checksum(&pseudo)
+    }
+
+    #[test]
+    fn syn_segment_layout() {
+        let source: IpAddr = "192.0.2.1".parse().unwrap();
+        let target: SocketAddr = "104.16.0.1:443".parse().unwrap();
+        let segment = build_segment(source, target, 45000, 0x01020304, TCP_SYN);
+        assert_eq!(segment.len(), SYN_PACKET_LEN);
+        assert_eq!(u16::from_be_bytes([segment[0], segment[1]]), 45000);
+        assert_eq!(u16::from_be_bytes([segment[2], segment[3]]), 443);
+        assert_eq!(&segment[4..8], &[1, 2, 3, 4]);
+        assert_eq!(segment[12] >> 4, 6);
+        assert_eq!(segment[13], TCP_SYN);
+        assert_eq!(&segment[20..24], &[2, 4, 0x05, 0xb4]);
+        assert_eq!(verify(source, target, &segment), 0);
+    }
+
+    #[test]
+    fn rst_segment_checksum() {
+        let source: IpAddr = "2001:db8::1".parse().unwrap();
+        let target: SocketAddr = "[2606:4700::1]:80".parse().unwrap();
+        let segment = build_segment(source, target, 45000, 7, TCP_RST);
+        assert_eq!(segment.len(), RST_PACKET_LEN);
+        assert_eq!(segment[12] >> 4, 5);
+        assert_eq!(segment[13], TCP_RST);
+        assert_eq!(verify(source, target, &segment), 0);
+    }
+}
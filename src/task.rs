@@ -0,0 +1,389 @@
+use crate::{
+    bind::Egress,
+    cfst_rpc::{IpResult, SpeedtestResponse},
+    hijack::{detect_interception, HijackConfig},
+    ip_catalog::IpCatalog,
+    ip_filter::IpFilter,
+    limiter::ProbeLimiter,
+    ping::{ip_cidr_to_ips, ping_ips, probe_ports, PingConfig, PingStats, ProbeMode},
+    probe::summarize_failures,
+    sampling::SampleConfig,
+    selection::{SelectionConfig, SelectionStrategy, SpeedCandidate},
+    server_comm::build_ip_result,
+    speed::{speed_one_ip, upload_one_ip, SpeedConfig},
+    traffic::traffic_exceeded,
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use std::{collections::HashMap, error::Error, net::IpAddr, sync::Arc, time::Duration};
+use tokio::time::{sleep_until, timeout, Instant};
+
+/// 执行单个测速任务所需的设置, 由主端下发的任务与本地设置合并而来。
+pub struct TaskRunner<'a> {
+    /// Cloudflare IP 段目录
+    pub catalog: &'a IpCatalog,
+    /// 合并了主端排除列表的允许 / 排除列表
+    pub ip_filter: &'a IpFilter,
+    /// 本次任务的采样设置, 各出口使用相同的种子以测试相同的候选 IP
+    pub sample_config: &'a SampleConfig,
+    /// 本次任务的探测设置, 绑定设置会被各出口覆盖
+    pub ping_config: &'a PingConfig,
+    /// 劫持检测设置
+    pub hijack_config: &'a HijackConfig,
+    /// 本次任务的上报 IP 选择设置
+    pub selection_config: &'a SelectionConfig,
+    /// 测速设置
+    pub speed_config: &'a SpeedConfig,
+    /// 各出口共享的探测限制器
+    pub limiter: &'a Arc<ProbeLimiter>,
+}
+
+impl TaskRunner<'_> {
+    /// 在指定出口上执行测速任务, 返回带有出口标签的IP结果。
+    ///
+    /// 探测与测速均使用该出口的绑定设置; 出口不具备 IPv6 连通性时跳过 IPv6 段。
+    pub async fn run(
+        &self,
+        speedtest_response: &SpeedtestResponse,
+        egress: &Egress,
+        ipv6_available: bool,
+    ) -> Result<IpResult, Box<dyn Error>> {
+        let mut ping_config = PingConfig {
+            binding: egress.binding.clone(),
+            ..self.ping_config.clone()
+        };
+        ping_config.check_fallback(egress.label(), ipv6_available);
+
+        // 展开并采样需要ping的IP列表, IP 在 Ping 时才惰性生成
+        let need_ping_ips = ip_cidr_to_ips(
+            speedtest_response.ip_ranges.clone(),
+            self.catalog,
+            self.ip_filter,
+            self.sample_config,
+            ipv6_available,
+        )
+        .await?;
+
+        info!(
+            "出口 {} 使用 {} 探测延迟",
+            egress.label(),
+            ping_config.mode.as_str()
+        );
+
+        // 对需要ping的IP进行ping测试, 符合延迟要求的IP直接流入测速
+        let mut ips_ping = ping_ips(
+            need_ping_ips,
+            speedtest_response.maximum_ping,
+            &ping_config,
+            self.limiter,
+        );
+        let mut qualified_count: usize = 0;
+
+        let concurrency = self
+            .speed_config
+            .concurrency_for(speedtest_response.minimum_mbps);
+        if concurrency > 1 {
+            info!(
+                "出口 {} 同时测速 {} 个 IP, 每个约分得 {:.0}Mbps 带宽",
+                egress.label(),
+                concurrency,
+                self.speed_config.share_mbps(concurrency)
+            );
+        }
+
+        // 按延迟顺序测试每个IP的速度, 直到选择策略认为可以停止
+        let mut tested: Vec<SpeedCandidate> = Vec::new();
+        let mut running = FuturesUnordered::new();
+        let mut in_flight: HashMap<IpAddr, Instant> = HashMap::new();
+        // 未得出速度的测速 (超时、被跳过或被取消) 同样占用过带宽, 按每个测速分得的带宽估算
+        let unfinished_mbps = self.speed_config.share_mbps(concurrency);
+        let mut windows: Vec<SpeedWindow> = Vec::new();
+        let mut ping_finished = false;
+        let search_start = Instant::now();
+        let budget_deadline = search_start + self.selection_config.time_budget;
+        let has_budget = self.selection_config.strategy == SelectionStrategy::TimeBudget;
+
+        loop {
+            // 流量配额用尽后不再开始新的测速, 已开始的测速照常完成
+            if !ping_finished {
+                if let Some(exceeded) = traffic_exceeded() {
+                    warn!("{}, 出口 {} 不再开始新的测速", exceeded, egress.label());
+                    ping_finished = true;
+                }
+            }
+            let can_start = !ping_finished
+                && running.len() < concurrency
+                && !self
+                    .selection_config
+                    .budget_exhausted(search_start.elapsed());
+            tokio::select! {
+                next = ips_ping.next(), if can_start => match next {
+                    Some((speed_ip, ping_stats)) => {
+                        qualified_count += 1;
+                        in_flight.insert(speed_ip, Instant::now());
+                        running.push(self.test_candidate(
+                            speedtest_response,
+                            &ping_config,
+                            speed_ip,
+                            ping_stats,
+                        ));
+                    }
+                    None => ping_finished = true,
+                },
+                Some((speed_ip, candidate)) = running.next(), if !running.is_empty() => {
+                    let started_at = in_flight.remove(&speed_ip).unwrap_or_else(Instant::now);
+                    let Some(candidate) = candidate else {
+                        windows.push(SpeedWindow {
+                            started_at,
+                            finished_at: Instant::now(),
+                            speed: unfinished_mbps,
+                        });
+                        continue;
+                    };
+                    tested.push(candidate);
+                    if self.selection_config.should_stop(
+                        &tested,
+                        speedtest_response.minimum_mbps,
+                        search_start.elapsed(),
+                    ) {
+                        break;
+                    }
+                }
+                // 等待探测结果期间测速时长用尽, 醒来后不再开始新的测速
+                _ = sleep_until(budget_deadline), if has_budget && can_start => {}
+                else => break,
+            }
+        }
+        // 提前结束时丢弃剩余的流与测速, 尚未完成的 Ping 与测速会被取消
+        drop(running);
+        drop(ips_ping);
+        info!(
+            "出口 {} 共测速 {} 个符合延迟要求的 IP",
+            egress.label(),
+            qualified_count
+        );
+
+        // 被取消的测速以当前时间作为结束时间参与重叠统计
+        let now = Instant::now();
+        windows.extend(tested.iter().map(|candidate| SpeedWindow {
+            started_at: candidate.started_at,
+            finished_at: candidate.finished_at,
+            speed: candidate.speed.max(0.0),
+        }));
+        windows.extend(in_flight.into_values().map(|started_at| SpeedWindow {
+            started_at,
+            finished_at: now,
+            speed: unfinished_mbps,
+        }));
+
+        let chosen = self
+            .selection_config
+            .choose(tested, speedtest_response.minimum_mbps);
+        match &chosen {
+            Some(candidate) => info!(
+                "出口 {} 按 {} 策略选中 IP {}, 速度 {:.0}Mbps, 延迟中位数 {}ms",
+                egress.label(),
+                self.selection_config.strategy.as_str(),
+                candidate.ip,
+                candidate.speed,
+                candidate.ping.median
+            ),
+            None => warn!("出口 {} 在测试完所有的 IP 后, 没有发现符合条件的 IP, 请检查您的网络环境, 或请求主端提供者降低最小带宽要求与 Ping 要求", egress.label()),
+        }
+
+        let mut ip_result = build_ip_result(
+            chosen
+                .as_ref()
+                .map(|candidate| candidate.ip.to_string())
+                .unwrap_or_default(),
+            chosen.as_ref().map(|candidate| candidate.ping.clone()),
+            chosen
+                .as_ref()
+                .map_or(-1, |candidate| candidate.speed.round() as i32),
+            chosen.as_ref().map_or(ping_config.mode, |candidate| {
+                ping_config.mode_for(candidate.ip)
+            }),
+        );
+        ip_result.egress = egress.name.clone();
+
+        if let Some(candidate) = &chosen {
+            let result = &candidate.result;
+            ip_result.stream_speeds = result.stream_mbps.iter().map(|s| *s as f32).collect();
+            ip_result.speed_mean = result.mean_mbps as f32;
+            ip_result.speed_peak = result.peak_mbps as f32;
+            ip_result.speed_intervals = result.interval_mbps.iter().map(|s| *s as f32).collect();
+            ip_result.suspected_interception = !candidate.signs.is_empty();
+            ip_result.interception_reasons = candidate
+                .signs
+                .iter()
+                .map(|sign| sign.to_string())
+                .collect();
+
+            // 统计与选中 IP 的测速时间重叠的测速, 判断测得的速度是否可能受到影响
+            let overlapping: Vec<&SpeedWindow> = windows
+                .iter()
+                .filter(|window| {
+                    window.started_at < candidate.finished_at
+                        && window.finished_at > candidate.started_at
+                })
+                .collect();
+            let total_mbps: f64 = overlapping.iter().map(|window| window.speed).sum();
+            ip_result.concurrent_speedtests = overlapping.len() as i32;
+            ip_result.speed_contended = self
+                .speed_config
+                .is_contended(overlapping.len(), total_mbps);
+            if ip_result.speed_contended {
+                warn!(
+                    "IP {} 测速时有 {} 个测速同时进行, 总速度 {:.0}Mbps 接近节点带宽, 测得的速度可能偏低",
+                    candidate.ip,
+                    overlapping.len(),
+                    total_mbps
+                );
+            }
+        }
+
+        // 使用 ICMP 探测时, 额外测量选中 IP 的 TCP 延迟, 与 ICMP 延迟一同上报
+        if let (
+            Some(SpeedCandidate {
+                ip, ping: stats, ..
+            }),
+            ProbeMode::Icmp,
+        ) = (
+            &chosen,
+            chosen.as_ref().map_or(ping_config.mode, |candidate| {
+                ping_config.mode_for(candidate.ip)
+            }),
+        ) {
+            let ip = *ip;
+            let tcp_config = PingConfig {
+                mode: ProbeMode::Tcp,
+                ..ping_config.clone()
+            };
+            ip_result.icmp_latency = stats.median as i32;
+            ip_result.latency = match probe_ports(
+                ip,
+                speedtest_response.maximum_ping,
+                &tcp_config,
+                self.limiter,
+            )
+            .await
+            {
+                Ok(tcp_stats) => tcp_stats.median as i32,
+                Err(results) => {
+                    warn!(
+                        "IP {} TCP 探测失败: {}",
+                        ip,
+                        summarize_failures(&results).join(", ")
+                    );
+                    -1
+                }
+            };
+        }
+
+        // 主端下发了上传 URL 且流量配额未用尽时, 对选中 IP 进行上传测速
+        let upload_allowed = match traffic_exceeded() {
+            Some(exceeded) if !speedtest_response.upload_url.is_empty() => {
+                warn!("{}, 跳过上传测速", exceeded);
+                false
+            }
+            _ => !speedtest_response.upload_url.is_empty(),
+        };
+        if let (Some(candidate), true) = (&chosen, upload_allowed) {
+            let upload_port = (ping_config.mode == ProbeMode::Tls).then_some(candidate.ping.port);
+            // 建立连接与发送完成后等待响应另需一定时间
+            ip_result.upload_speed = match timeout(
+                self.speed_config.upload_time + Duration::from_secs(10),
+                upload_one_ip(
+                    speedtest_response.upload_url.clone(),
+                    candidate.ip,
+                    upload_port,
+                    &ping_config.binding,
+                    self.speed_config,
+                ),
+            )
+            .await
+            {
+                Ok(speed) if speed >= 0.0 => speed.round() as i32,
+                Ok(_) => -1,
+                Err(e) => {
+                    error!("IP {} 上传测速超时: {}", candidate.ip, e);
+                    -1
+                }
+            };
+        }
+
+        Ok(ip_result)
+    }
+
+    // 对单个候选 IP 进行劫持检测与测速, 疑似被劫持而跳过或测速超时时返回 None
+    async fn test_candidate(
+        &self,
+        speedtest_response: &SpeedtestResponse,
+        ping_config: &PingConfig,
+        speed_ip: IpAddr,
+        ping_stats: PingStats,
+    ) -> (IpAddr, Option<SpeedCandidate>) {
+        let started_at = Instant::now();
+
+        // 劫持检测, 疑似被劫持的 IP 默认只做标记
+        let signs = detect_interception(
+            speed_ip,
+            &ping_stats,
+            ping_config.mode_for(speed_ip),
+            &ping_config.sni,
+            &ping_config.binding,
+            self.hijack_config,
+        )
+        .await;
+        if !signs.is_empty() {
+            let reasons: Vec<String> = signs.iter().map(|sign| sign.to_string()).collect();
+            if self.hijack_config.skip_suspected {
+                warn!("IP {} 疑似被劫持, 跳过: {}", speed_ip, reasons.join("; "));
+                return (speed_ip, None);
+            }
+            warn!("IP {} 疑似被劫持: {}", speed_ip, reasons.join("; "));
+        }
+
+        // TLS 探测的端口均为 HTTPS 端口, 测速时使用该 IP 表现最好的端口
+        let speed_port = (ping_config.mode == ProbeMode::Tls).then_some(ping_stats.port);
+        let speed = match timeout(
+            Duration::from_secs(12),
+            speed_one_ip(
+                speedtest_response.speed_url.clone(),
+                speed_ip,
+                speed_port,
+                10,
+                speedtest_response.minimum_mbps,
+                &ping_config.binding,
+                self.speed_config,
+            ),
+        )
+        .await
+        {
+            Ok(tmp) => tmp,
+            Err(e) => {
+                error!("IP {} 测速超时: {}", speed_ip, e);
+                return (speed_ip, None);
+            }
+        };
+
+        let candidate = SpeedCandidate {
+            ip: speed_ip,
+            ping: ping_stats,
+            speed: speed.mbps,
+            result: speed,
+            signs,
+            started_at,
+            finished_at: Instant::now(),
+        };
+        (speed_ip, Some(candidate))
+    }
+}
+
+// 一次测速占用带宽的时间段
+struct SpeedWindow {
+    started_at: Instant,
+    finished_at: Instant,
+    speed: f64,
+}
@@ -0,0 +1,231 @@
+use crate::{
+    bind::SocketBinding,
+    probe::{ProbeOutcome, ProbeResult},
+};
+
+use log::debug;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{timeout, Instant},
+};
+use tokio_rustls::{
+    rustls::{self, pki_types::ServerName},
+    TlsConnector,
+};
+
+// 未能从任务中获取 SNI 时使用的默认域名
+pub const DEFAULT_PROBE_SNI: &str = "speed.cloudflare.com";
+
+// /cdn-cgi/trace 响应读取上限, 正常响应不足 1KB
+const TRACE_RESPONSE_LIMIT: usize = 8 * 1024;
+
+// TCP 连接与 TLS 1.3 握手各需 1 个往返, 请求 trace 再需 1 个往返。
+// 探测超时按往返次数放大, 使单次往返的延迟上限与 TCP 连接探测一致
+const HANDSHAKE_ROUND_TRIPS: u32 = 2;
+const TRACE_ROUND_TRIPS: u32 = 1;
+
+/// 单次 TLS 探测各阶段的耗时 (毫秒) 及 trace 信息。
+#[derive(Debug, Clone)]
+pub struct TlsTiming {
+    /// TCP 连接耗时
+    pub connect: u128,
+    /// TLS 握手耗时
+    pub handshake: u128,
+    /// 发送 /cdn-cgi/trace 请求到收到首字节的耗时, 未请求 trace 时为 None
+    pub ttfb: Option<u128>,
+    /// trace 响应中的 Cloudflare 数据中心代码
+    pub colo: Option<String>,
+    /// TLS 证书校验失败的原因, 证书有效时为 None
+    pub certificate_error: Option<String>,
+}
+
+impl TlsTiming {
+    /// 返回从发起连接到 TLS 握手完成的总耗时, 作为该次探测的延迟。
+    pub fn latency(&self) -> u128 {
+        self.connect + self.handshake
+    }
+
+    /// 对多次探测的耗时取中位数, 数据中心代码取出现次数最多的一个, 任一次证书校验失败即保留其原因。
+    pub fn median(timings: &[TlsTiming]) -> Option<TlsTiming> {
+        if timings.is_empty() {
+            return None;
+        }
+
+        let median_of = |mut values: Vec<u128>| -> Option<u128> {
+            if values.is_empty() {
+                return None;
+            }
+            values.sort_unstable();
+            Some(values[(values.len() - 1) / 2])
+        };
+
+        let mut colos: Vec<&String> = timings.iter().filter_map(|t| t.colo.as_ref()).collect();
+        colos.sort();
+        let colo = colos
+            .chunk_by(|a, b| a == b)
+            .max_by_key(|group| group.len())
+            .map(|group| group[0].clone());
+
+        Some(TlsTiming {
+            connect: median_of(timings.iter().map(|t| t.connect).collect())?,
+            handshake: median_of(timings.iter().map(|t| t.handshake).collect())?,
+            ttfb: median_of(timings.iter().filter_map(|t| t.ttfb).collect()),
+            colo,
+            certificate_error: timings.iter().find_map(|t| t.certificate_error.clone()),
+        })
+    }
+}
+
+/// 返回使用 webpki 根证书的共享 TLS 连接器。
+pub fn tls_connector() -> TlsConnector {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    let config = CONFIG.get_or_init(|| {
+        let mut root_cert_store = rustls::RootCertStore::empty();
+        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(root_cert_store)
+                .with_no_client_auth(),
+        )
+    });
+    TlsConnector::from(config.clone())
+}
+
+/// 对单个 IP 的指定端口进行一次 TLS 探测, 返回探测记录, 成功时附带各阶段耗时。
+///
+/// 依次记录 TCP 连接与 TLS 握手耗时; 若 `trace` 为 true, 还会请求 `/cdn-cgi/trace`,
+/// 记录首字节耗时并解析响应中的 `colo=` 字段, 以确认应答的 Cloudflare 数据中心。
+/// 证书校验失败的 IP 仍视为探测成功, 由劫持检测根据 `certificate_error` 标记。
+/// `timeout_ms` 为单次往返的延迟上限, 整个探测的超时按所需往返次数放大。
+pub async fn tls_probe(
+    ip: IpAddr,
+    port: u16,
+    sni: &str,
+    trace: bool,
+    timeout_ms: i32,
+    binding: &SocketBinding,
+) -> ProbeResult {
+    let addr = SocketAddr::new(ip, port);
+    let round_trips = HANDSHAKE_ROUND_TRIPS + if trace { TRACE_ROUND_TRIPS } else { 0 };
+    let time_out = Duration::from_millis(timeout_ms as u64) * round_trips;
+    let started_at = SystemTime::now();
+    let (result, tls) = match timeout(time_out, tls_handshake(addr, sni, trace, binding)).await {
+        Ok(Ok(timing)) => {
+            if let Some(e) = &timing.certificate_error {
+                debug!("IP {} TLS 证书校验失败, 疑似被劫持: {}", ip, e);
+            }
+            (
+                Ok(Duration::from_millis(timing.latency() as u64)),
+                Some(timing),
+            )
+        }
+        Ok(Err(e)) => {
+            debug!("IP {} TLS 探测失败: {}", ip, e);
+            (Err(e), None)
+        }
+        Err(_) => (Err(io::ErrorKind::TimedOut.into()), None),
+    };
+
+    let mut probe = ProbeResult::new(addr, started_at, ProbeOutcome::from_io(result));
+    probe.tls = tls;
+    probe
+}
+
+/// 按绑定设置与指定地址完成一次 TLS 握手 (校验证书), 可选请求 `/cdn-cgi/trace`, 返回各阶段耗时。
+///
+/// 证书校验失败时不视为错误, 返回连接与握手耗时并在 `certificate_error` 中记录原因, 不再请求 trace。
+pub async fn tls_handshake(
+    addr: SocketAddr,
+    sni: &str,
+    trace: bool,
+    binding: &SocketBinding,
+) -> io::Result<TlsTiming> {
+    let server_name = ServerName::try_from(sni.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let start = Instant::now();
+    let stream = binding.connect(addr).await?;
+    let connect = start.elapsed().as_millis();
+
+    let start = Instant::now();
+    let mut stream = match tls_connector().connect(server_name, stream).await {
+        Ok(stream) => stream,
+        Err(e) if is_certificate_error(&e) => {
+            return Ok(TlsTiming {
+                connect,
+                handshake: start.elapsed().as_millis(),
+                ttfb: None,
+                colo: None,
+                certificate_error: Some(e.to_string()),
+            });
+        }
+        Err(e) => return Err(e),
+    };
+    let handshake = start.elapsed().as_millis();
+
+    let mut timing = TlsTiming {
+        connect,
+        handshake,
+        ttfb: None,
+        colo: None,
+        certificate_error: None,
+    };
+
+    if trace {
+        let request = format!(
+            "GET /cdn-cgi/trace HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            sni
+        );
+        let start = Instant::now();
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response: Vec<u8> = Vec::new();
+        let mut buffer = [0; 1024];
+        loop {
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            if timing.ttfb.is_none() {
+                timing.ttfb = Some(start.elapsed().as_millis());
+            }
+            response.extend_from_slice(&buffer[..n]);
+            if response.len() >= TRACE_RESPONSE_LIMIT {
+                break;
+            }
+        }
+
+        timing.colo = parse_trace_field(&String::from_utf8_lossy(&response), "colo");
+    }
+
+    let _ = stream.shutdown().await;
+    Ok(timing)
+}
+
+// 判断 TLS 握手错误是否由证书校验失败引起
+fn is_certificate_error(error: &io::Error) -> bool {
+    matches!(
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(_))
+    )
+}
+
+/// 从 /cdn-cgi/trace 响应中解析指定字段, 响应正文为每行一个 `key=value`。
+pub fn parse_trace_field(response: &str, key: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (k, v) = line.trim().split_once('=')?;
+        if k == key {
+            Some(v.to_string())
+        } else {
+            None
+        }
+    })
+}
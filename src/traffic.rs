@@ -0,0 +1,488 @@
+use crate::args::Args;
+
+use log::{info, warn};
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// 1 GB 的字节数
+const BYTES_PER_GB: f64 = 1_000_000_000.0;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+// 设置了流量配额但未指定统计文件时使用的路径
+const DEFAULT_TRAFFIC_FILE: &str = "/var/lib/cfst_slave/traffic";
+
+// 测速按收发的 HTTP 数据计量, 另按该比例计入 TLS 记录与 TCP/IP 头部的开销,
+// 与探测按报文大小估算的流量口径一致 (1460 字节 MSS 下头部约占 4%, TLS 记录约占 0.5%)
+const PAYLOAD_OVERHEAD: f64 = 1.05;
+
+// 全局流量计量器, 设置了流量配额或统计文件时在启动时初始化; 未初始化时记录的流量会被忽略
+static TRAFFIC: OnceLock<TrafficMeter> = OnceLock::new();
+
+/// 流量计量器, 统计探测与测速使用的流量, 按 UTC 日期划分统计周期并持久化到文件。
+pub struct TrafficMeter {
+    path: PathBuf,
+    daily_quota: Option<u64>,
+    monthly_quota: Option<u64>,
+    reset_day: u32,
+    // 尚未计入统计周期的流量, 避免在读取路径上加锁
+    pending: AtomicU64,
+    state: Mutex<TrafficState>,
+}
+
+// 当前统计周期及其已用流量
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TrafficState {
+    day: Date,
+    day_bytes: u64,
+    // 当前计费月的起始日期
+    month: Date,
+    month_bytes: u64,
+}
+
+/// 某个统计周期的流量配额已用尽。
+#[derive(Debug, Clone)]
+pub struct QuotaExceeded {
+    /// 统计周期的名称
+    pub period: &'static str,
+    /// 已用流量 (字节)
+    pub used: u64,
+    /// 流量配额 (字节)
+    pub quota: u64,
+    /// 配额恢复的时间
+    pub resets_at: SystemTime,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let resets_at = self
+            .resets_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        write!(
+            f,
+            "{}流量配额已用尽 (已用 {:.2}GB / 配额 {:.2}GB), 将于 {} UTC 恢复",
+            self.period,
+            self.used as f64 / BYTES_PER_GB,
+            self.quota as f64 / BYTES_PER_GB,
+            Date::from_days(resets_at.div_euclid(SECONDS_PER_DAY))
+        )
+    }
+}
+
+impl TrafficMeter {
+    /// 根据命令行参数构建流量计量器, 并从文件恢复之前的统计。
+    ///
+    /// 未设置流量配额也未指定统计文件时不统计流量, 返回 None。
+    pub fn from_args(args: &Args) -> Result<Option<TrafficMeter>, Box<dyn Error>> {
+        let quota = |gb: f64| (gb > 0.0).then_some((gb * BYTES_PER_GB) as u64);
+        let daily_quota = quota(args.daily_quota_gb);
+        let monthly_quota = quota(args.monthly_quota_gb);
+        let path = match &args.traffic_file {
+            Some(path) => PathBuf::from(path),
+            None if daily_quota.is_some() || monthly_quota.is_some() => {
+                PathBuf::from(DEFAULT_TRAFFIC_FILE)
+            }
+            None => return Ok(None),
+        };
+
+        let reset_day = args.quota_reset_day;
+        let today = Date::today();
+        let fresh = TrafficState {
+            day: today,
+            day_bytes: 0,
+            month: today.billing_month(reset_day),
+            month_bytes: 0,
+        };
+
+        let state = match fs::read_to_string(&path) {
+            Ok(content) => {
+                let mut state = parse_state(&content)
+                    .map_err(|e| format!("无法解析流量统计文件 {}: {}", path.display(), e))?;
+                state.roll_over(today, reset_day);
+                state
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fresh,
+            Err(e) => return Err(format!("无法读取流量统计文件 {}: {}", path.display(), e).into()),
+        };
+
+        Ok(Some(TrafficMeter {
+            path,
+            daily_quota,
+            monthly_quota,
+            reset_day,
+            pending: AtomicU64::new(0),
+            state: Mutex::new(state),
+        }))
+    }
+
+    /// 记录使用的流量。
+    pub fn record(&self, bytes: u64) {
+        self.pending.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// 返回今日与本计费月已用的流量 (字节)。
+    pub fn usage(&self) -> (u64, u64) {
+        let state = self.current();
+        (state.day_bytes, state.month_bytes)
+    }
+
+    /// 在日志中记录今日与本计费月已用的流量。
+    pub fn log_usage(&self) {
+        let (day, month) = self.usage();
+        info!(
+            "今日已用流量 {}, 本月已用流量 {}",
+            format_gb(day),
+            format_gb(month)
+        );
+    }
+
+    /// 检查流量配额, 已用尽时返回用尽的周期与恢复时间; 每日与每月配额均用尽时返回较晚恢复的一个。
+    pub fn exceeded(&self) -> Option<QuotaExceeded> {
+        let state = self.current();
+        let monthly = self
+            .monthly_quota
+            .filter(|quota| state.month_bytes >= *quota)
+            .map(|quota| QuotaExceeded {
+                period: "本月",
+                used: state.month_bytes,
+                quota,
+                resets_at: state.month.next_billing_month(self.reset_day).start(),
+            });
+        let daily = self
+            .daily_quota
+            .filter(|quota| state.day_bytes >= *quota)
+            .map(|quota| QuotaExceeded {
+                period: "今日",
+                used: state.day_bytes,
+                quota,
+                resets_at: Date::from_days(state.day.days + 1).start(),
+            });
+        monthly.or(daily)
+    }
+
+    /// 将统计写入文件, 先写入临时文件再替换, 避免写入中途退出导致文件损坏。
+    pub fn save(&self) -> io::Result<()> {
+        let state = self.current();
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = temp_path(&self.path);
+        fs::write(&temp, format_state(&state))?;
+        fs::rename(&temp, &self.path)
+    }
+
+    // 将尚未计入的流量计入当前统计周期, 跨越周期时先清零
+    fn current(&self) -> TrafficState {
+        let mut state = self.state.lock().unwrap();
+        state.roll_over(Date::today(), self.reset_day);
+        let pending = self.pending.swap(0, Ordering::Relaxed);
+        state.day_bytes += pending;
+        state.month_bytes += pending;
+        *state
+    }
+}
+
+impl TrafficState {
+    fn roll_over(&mut self, today: Date, reset_day: u32) {
+        if self.day != today {
+            info!("进入新的一天, 今日流量统计清零");
+            self.day = today;
+            self.day_bytes = 0;
+        }
+        let month = today.billing_month(reset_day);
+        if self.month != month {
+            info!("进入新的计费月, 本月流量统计清零");
+            self.month = month;
+            self.month_bytes = 0;
+        }
+    }
+}
+
+/// 初始化全局流量计量器。
+pub fn init_traffic(meter: TrafficMeter) {
+    if TRAFFIC.set(meter).is_err() {
+        warn!("流量计量器已经初始化");
+    }
+}
+
+/// 返回全局流量计量器。
+pub fn traffic() -> Option<&'static TrafficMeter> {
+    TRAFFIC.get()
+}
+
+/// 向全局流量计量器记录使用的流量。
+pub fn record_traffic(bytes: u64) {
+    if let Some(meter) = TRAFFIC.get() {
+        meter.record(bytes);
+    }
+}
+
+/// 向全局流量计量器记录测速收发的 HTTP 数据, 按固定比例计入 TLS 与 TCP/IP 的开销。
+pub fn record_payload_traffic(bytes: u64) {
+    record_traffic((bytes as f64 * PAYLOAD_OVERHEAD).ceil() as u64);
+}
+
+/// 保存全局流量计量器的统计, 失败时记录警告。
+pub fn save_traffic() {
+    if let Some(meter) = TRAFFIC.get() {
+        if let Err(e) = meter.save() {
+            warn!("无法保存流量统计到 {}: {}", meter.path.display(), e);
+        }
+    }
+}
+
+/// 检查全局流量计量器的配额, 已用尽时返回原因。
+pub fn traffic_exceeded() -> Option<QuotaExceeded> {
+    TRAFFIC.get().and_then(|meter| meter.exceeded())
+}
+
+/// 格式化流量用于日志。
+pub fn format_gb(bytes: u64) -> String {
+    format!("{:.2}GB", bytes as f64 / BYTES_PER_GB)
+}
+
+// 以 1970-01-01 起的天数表示的 UTC 日期
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Date {
+    days: i64,
+}
+
+impl Date {
+    fn today() -> Date {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        Date::from_days(secs.div_euclid(SECONDS_PER_DAY))
+    }
+
+    fn from_days(days: i64) -> Date {
+        Date { days }
+    }
+
+    // 由年月日构建日期, 算法见 https://howardhinnant.github.io/date_algorithms.html
+    fn from_ymd(year: i64, month: u32, day: u32) -> Date {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let month = month as i64;
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        Date::from_days(era * 146_097 + doe - 719_468)
+    }
+
+    fn ymd(&self) -> (i64, u32, u32) {
+        let z = self.days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year, month, day)
+    }
+
+    // 返回该日期所在计费月的起始日期, 计费月从每月的 reset_day 日开始
+    fn billing_month(&self, reset_day: u32) -> Date {
+        let (year, month, day) = self.ymd();
+        if day >= reset_day {
+            Date::from_ymd(year, month, reset_day)
+        } else if month == 1 {
+            Date::from_ymd(year - 1, 12, reset_day)
+        } else {
+            Date::from_ymd(year, month - 1, reset_day)
+        }
+    }
+
+    // 返回下一个计费月的起始日期
+    fn next_billing_month(&self, reset_day: u32) -> Date {
+        let (year, month, _) = self.ymd();
+        if month == 12 {
+            Date::from_ymd(year + 1, 1, reset_day)
+        } else {
+            Date::from_ymd(year, month + 1, reset_day)
+        }
+    }
+
+    // 返回该日期 0 点 (UTC) 的时间
+    fn start(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs((self.days.max(0) * SECONDS_PER_DAY) as u64)
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.ymd();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+impl std::str::FromStr for Date {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Date, String> {
+        let parts: Vec<&str> = s.trim().split('-').collect();
+        let [year, month, day] = parts.as_slice() else {
+            return Err(format!("无效的日期: {}", s));
+        };
+        let invalid = |_| format!("无效的日期: {}", s);
+        Ok(Date::from_ymd(
+            year.parse().map_err(invalid)?,
+            month.parse().map_err(invalid)?,
+            day.parse().map_err(invalid)?,
+        ))
+    }
+}
+
+// 统计文件为 `键=值` 格式, 每行一项
+fn format_state(state: &TrafficState) -> String {
+    format!(
+        "day={}\nday_bytes={}\nmonth={}\nmonth_bytes={}\n",
+        state.day, state.day_bytes, state.month, state.month_bytes
+    )
+}
+
+fn parse_state(content: &str) -> Result<TrafficState, String> {
+    let mut day: Option<Date> = None;
+    let mut day_bytes: u64 = 0;
+    let mut month: Option<Date> = None;
+    let mut month_bytes: u64 = 0;
+    for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("无效的行: {}", line))?;
+        let invalid = |_| format!("无效的行: {}", line);
+        match key.trim() {
+            "day" => day = Some(value.parse()?),
+            "day_bytes" => day_bytes = value.trim().parse().map_err(invalid)?,
+            "month" => month = Some(value.parse()?),
+            "month_bytes" => month_bytes = value.trim().parse().map_err(invalid)?,
+            _ => {}
+        }
+    }
+    Ok(TrafficState {
+        day: day.ok_or("缺少 day")?,
+        day_bytes,
+        month: month.ok_or("缺少 month")?,
+        month_bytes,
+    })
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> Date {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn civil_date_round_trip() {
+        assert_eq!(Date::from_ymd(1970, 1, 1).days, 0);
+        assert_eq!(Date::from_ymd(2000, 3, 1).days, 11_017);
+        assert_eq!(Date::from_days(-1).to_string(), "1969-12-31");
+        for s in [
+            "2000-02-29",
+            "2024-02-29",
+            "2023-12-31",
+            "2100-03-01",
+            "2400-02-29",
+        ] {
+            assert_eq!(date(s).to_string(), s);
+        }
+        // 逐日递增时年月日连续, 覆盖闰年与世纪年
+        let start = Date::from_ymd(1999, 12, 1);
+        let mut previous = start.ymd();
+        for days in start.days + 1..Date::from_ymd(2101, 1, 1).days {
+            let (year, month, day) = Date::from_days(days).ymd();
+            let (py, pm, pd) = previous;
+            let next_day = year == py && month == pm && day == pd + 1;
+            let next_month = year == py && month == pm + 1 && day == 1;
+            let next_year = year == py + 1 && month == 1 && pm == 12 && day == 1;
+            assert!(
+                next_day || next_month || next_year,
+                "{:?} -> {:?}",
+                previous,
+                (year, month, day)
+            );
+            assert_eq!(Date::from_ymd(year, month, day).days, days);
+            previous = (year, month, day);
+        }
+    }
+
+    #[test]
+    fn date_parse_rejects_invalid() {
+        assert!("2024-01".parse::<Date>().is_err());
+        assert!("2024-xx-01".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn billing_month_rollover() {
+        assert_eq!(date("2024-03-15").billing_month(1), date("2024-03-01"));
+        assert_eq!(date("2024-03-14").billing_month(15), date("2024-02-15"));
+        assert_eq!(date("2024-03-15").billing_month(15), date("2024-03-15"));
+        assert_eq!(date("2024-01-10").billing_month(28), date("2023-12-28"));
+        assert_eq!(date("2024-12-31").billing_month(28), date("2024-12-28"));
+
+        assert_eq!(
+            date("2024-01-28").next_billing_month(28),
+            date("2024-02-28")
+        );
+        assert_eq!(
+            date("2023-12-28").next_billing_month(28),
+            date("2024-01-28")
+        );
+        assert_eq!(date("2024-12-01").next_billing_month(1), date("2025-01-01"));
+    }
+
+    #[test]
+    fn state_rolls_over_day_and_month() {
+        let mut state = TrafficState {
+            day: date("2024-02-28"),
+            day_bytes: 10,
+            month: date("2024-02-05"),
+            month_bytes: 100,
+        };
+
+        state.roll_over(date("2024-02-28"), 5);
+        assert_eq!((state.day_bytes, state.month_bytes), (10, 100));
+
+        state.roll_over(date("2024-02-29"), 5);
+        assert_eq!(state.day, date("2024-02-29"));
+        assert_eq!((state.day_bytes, state.month_bytes), (0, 100));
+
+        state.day_bytes = 10;
+        state.roll_over(date("2024-03-05"), 5);
+        assert_eq!(state.month, date("2024-03-05"));
+        assert_eq!((state.day_bytes, state.month_bytes), (0, 0));
+    }
+
+    #[test]
+    fn state_file_round_trip() {
+        let state = TrafficState {
+            day: date("2024-07-04"),
+            day_bytes: 123,
+            month: date("2024-07-01"),
+            month_bytes: 456_789,
+        };
+        assert_eq!(parse_state(&format_state(&state)).unwrap(), state);
+        assert!(parse_state("day=2024-07-04\n").is_err());
+        assert!(parse_state("day=2024-07-04\nmonth=2024-07-01\nday_bytes=x\n").is_err());
+    }
+}